@@ -0,0 +1,3 @@
+DROP TABLE rooms;
+ALTER TABLE IF EXISTS rooms_legacy RENAME TO rooms;
+ALTER INDEX IF EXISTS rooms_legacy_pkey RENAME TO rooms_pkey;
@@ -0,0 +1,21 @@
+-- rooms created by earlier setup had serial id and no checkpoint.
+-- That table is kept as rooms_legacy, so that nothing in it is lost
+DO $$
+BEGIN
+  IF EXISTS (
+    SELECT 1 FROM information_schema.columns
+    WHERE table_name = 'rooms' AND column_name = 'id' AND data_type = 'integer'
+  ) THEN
+    ALTER TABLE rooms RENAME TO rooms_legacy;
+    ALTER INDEX rooms_pkey RENAME TO rooms_legacy_pkey;
+  END IF;
+END $$;
+
+-- fails if any other rooms table is left, instead of using it as is
+CREATE TABLE rooms (
+  id         VARCHAR PRIMARY KEY,
+  instance   VARCHAR NOT NULL,
+  name       VARCHAR NOT NULL,
+  players    TEXT[]  NOT NULL,
+  checkpoint TEXT    NOT NULL
+);
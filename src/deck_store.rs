@@ -0,0 +1,47 @@
+/**
+ * ゲームで使うデッキの読み込み
+ * decks, belongings, cards テーブルからデッキのカードを読む
+ */
+use crate::models::Deck;
+use crate::schema::{belongings, cards, decks};
+use crate::DbPool;
+use card_playroom_server::websocket::deck_store::{DeckCard, DeckStore};
+use diesel::prelude::*;
+use std::error::Error;
+
+/// Decks in `decks` table
+pub struct DbDeckStore {
+    pool: DbPool,
+}
+
+impl DbDeckStore {
+    pub fn new(pool: DbPool) -> DbDeckStore {
+        DbDeckStore { pool }
+    }
+}
+
+impl DeckStore for DbDeckStore {
+    fn load(&self, deck_id: i32) -> Result<Option<Vec<DeckCard>>, Box<dyn Error>> {
+        let conn = self.pool.get()?;
+        let deck = decks::table.find(deck_id).first::<Deck>(&conn).optional()?;
+        if deck.is_none() {
+            return Ok(None);
+        }
+        let rows = belongings::table
+            .inner_join(cards::table)
+            .filter(belongings::deck_id.eq(deck_id))
+            .order_by(belongings::id.asc())
+            .select((cards::id, cards::face, cards::back, belongings::num))
+            .load::<(i32, String, String, i32)>(&conn)?;
+        Ok(Some(
+            rows.into_iter()
+                .map(|(card_id, face, back, num)| DeckCard {
+                    card_id,
+                    face,
+                    back,
+                    num: num.max(0) as usize,
+                })
+                .collect(),
+        ))
+    }
+}
@@ -3,6 +3,7 @@ extern crate diesel;
 extern crate dotenv;
 
 use std::env;
+use std::sync::Arc;
 
 use actix_cors::Cors;
 use actix_web::middleware::Logger;
@@ -18,10 +19,13 @@ use card_playroom_server::websocket;
 
 pub mod card;
 pub mod deck;
+pub mod deck_store;
 pub mod edit_deck;
 pub mod graphql;
 pub mod index;
+pub mod message_bus;
 pub mod models;
+pub mod room_store;
 pub mod schema;
 pub mod upload;
 
@@ -37,7 +41,23 @@ async fn main() -> std::io::Result<()> {
     let db_pool = create_db_pool();
 
     // Start game server actor
-    let ws_server = websocket::room_manager::ChatServer::default().start();
+    let ws_server = websocket::room_manager::ChatServer::new(
+        websocket::room_manager::ChatServerConfig::from_env(),
+    )
+    .with_store(Arc::new(room_store::DbRoomStore::new(db_pool.clone())))
+    .with_decks(Arc::new(deck_store::DbDeckStore::new(db_pool.clone())));
+    // Share rooms with other instances through database
+    let ws_server = match dotenv::var("MESSAGE_BUS").as_deref() {
+        Ok("postgres") => {
+            let database_url = dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set");
+            ws_server.with_bus(Arc::new(message_bus::PgBus::new(
+                &database_url,
+                db_pool.clone(),
+            )))
+        }
+        _ => ws_server,
+    }
+    .start();
 
     // Start tcp server in separate thread
     let srv = ws_server.clone();
@@ -0,0 +1,228 @@
+/**
+ * インスタンス間のメッセージバス
+ * Postgres の LISTEN/NOTIFY でルームとロビーを複数のサーバーで共有する
+ */
+use crate::DbPool;
+use actix::Recipient;
+use card_playroom_server::websocket::message_bus::{BusMessage, MessageBus};
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use postgres::fallible_iterator::FallibleIterator;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Channel every instance listens on
+const CHANNEL: &str = "card_playroom";
+/// Longest part of message in bytes, Postgres refuses payloads of 8000 bytes
+const MAX_PART_LEN: usize = 7000;
+/// How long to wait before listening again after connection is lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long parts of a message wait for the rest of them
+const PARTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bus over Postgres LISTEN/NOTIFY. Notifications are sent with the diesel
+/// pool and read on a connection of their own. Messages too long for one
+/// notification are sent in parts, each starting with "<id> <index> <count> "
+pub struct PgBus {
+    database_url: String,
+    /// payloads waiting to be sent, so that publishers do not wait for database
+    queue: Mutex<mpsc::Sender<String>>,
+}
+
+impl PgBus {
+    pub fn new(database_url: &str, pool: DbPool) -> PgBus {
+        let (queue, payloads) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for payload in payloads {
+                if let Err(error) = notify(&pool, &payload) {
+                    println!("Failed to notify: {}", error);
+                }
+            }
+        });
+        PgBus {
+            database_url: database_url.to_string(),
+            queue: Mutex::new(queue),
+        }
+    }
+}
+
+impl MessageBus for PgBus {
+    fn publish(&self, message: &BusMessage) -> Result<(), Box<dyn Error>> {
+        let message = serde_json::to_string(message)?;
+        let queue = self.queue.lock().map_err(|_| "bus is poisoned")?;
+        for part in split(&message) {
+            queue.send(part)?;
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, recipient: Recipient<BusMessage>) -> Result<(), Box<dyn Error>> {
+        let database_url = self.database_url.clone();
+        thread::spawn(move || {
+            while recipient.connected() {
+                if let Err(error) = listen(&database_url, &recipient) {
+                    println!("Lost message bus connection: {}", error);
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+fn notify(pool: &DbPool, payload: &str) -> Result<(), Box<dyn Error>> {
+    let conn = pool.get()?;
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(CHANNEL)
+        .bind::<Text, _>(payload)
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Pass notifications to `recipient` until connection is lost or
+/// recipient is stopped
+fn listen(database_url: &str, recipient: &Recipient<BusMessage>) -> Result<(), Box<dyn Error>> {
+    let mut client = postgres::Client::connect(database_url, postgres::NoTls)?;
+    client.batch_execute(&format!("LISTEN {}", CHANNEL))?;
+    let mut parts = Parts::default();
+    let mut notifications = client.notifications();
+    let mut iter = notifications.blocking_iter();
+    while let Some(notification) = iter.next()? {
+        let message = match parts.join(notification.payload(), Instant::now()) {
+            Some(message) => message,
+            None => continue,
+        };
+        match serde_json::from_str(&message) {
+            Ok(message) => {
+                if recipient.do_send(message).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(error) => println!("Malformed bus message: {}", error),
+        }
+    }
+    Err("connection is closed".into())
+}
+
+/// Split message into payloads short enough for notification
+fn split(message: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = message;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(MAX_PART_LEN);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    let id = Uuid::new_v4();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| format!("{} {} {} {}", id, index, chunks.len(), chunk))
+        .collect()
+}
+
+/// Parts of message received so far
+struct Partial {
+    /// when the first part arrived
+    started: Instant,
+    chunks: Vec<String>,
+}
+
+/// Parts of messages not fully received yet
+#[derive(Default)]
+struct Parts {
+    messages: HashMap<Uuid, Partial>,
+}
+
+impl Parts {
+    /// Whole message once its last part arrives at `now`. Messages whose
+    /// other parts do not arrive within `PARTS_TIMEOUT`, e.g. because sender
+    /// stopped, are dropped
+    fn join(&mut self, payload: &str, now: Instant) -> Option<String> {
+        self.messages
+            .retain(|_, partial| now.duration_since(partial.started) < PARTS_TIMEOUT);
+        let mut fields = payload.splitn(4, ' ');
+        let id = Uuid::parse_str(fields.next()?).ok()?;
+        let index: usize = fields.next()?.parse().ok()?;
+        let count: usize = fields.next()?.parse().ok()?;
+        let chunk = fields.next()?;
+        if count == 1 {
+            return Some(chunk.to_string());
+        }
+        let partial = self.messages.entry(id).or_insert_with(|| Partial {
+            started: now,
+            chunks: Vec::new(),
+        });
+        // parts from one instance arrive in order, the message is broken otherwise
+        if partial.chunks.len() != index {
+            self.messages.remove(&id);
+            return None;
+        }
+        partial.chunks.push(chunk.to_string());
+        if partial.chunks.len() < count {
+            return None;
+        }
+        self.messages
+            .remove(&id)
+            .map(|partial| partial.chunks.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_message_is_sent_whole() {
+        let payloads = split("{}");
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(
+            Parts::default().join(&payloads[0], Instant::now()),
+            Some("{}".to_string())
+        );
+    }
+
+    #[test]
+    fn long_message_is_joined_from_parts() {
+        // 3 bytes each, parts must not cut them
+        let message = "あ".repeat(5000);
+        let payloads = split(&message);
+        assert_eq!(payloads.len(), 3);
+        let mut parts = Parts::default();
+        let now = Instant::now();
+        assert_eq!(parts.join(&payloads[0], now), None);
+        assert_eq!(parts.join(&payloads[1], now), None);
+        assert_eq!(parts.join(&payloads[2], now), Some(message));
+        assert!(parts.messages.is_empty());
+    }
+
+    #[test]
+    fn message_with_missing_part_is_dropped() {
+        let payloads = split(&"a".repeat(MAX_PART_LEN * 3));
+        let mut parts = Parts::default();
+        let now = Instant::now();
+        assert_eq!(parts.join(&payloads[0], now), None);
+        assert_eq!(parts.join(&payloads[2], now), None);
+        assert!(parts.messages.is_empty());
+    }
+
+    #[test]
+    fn incomplete_message_expires() {
+        let payloads = split(&"a".repeat(MAX_PART_LEN * 2));
+        let mut parts = Parts::default();
+        let now = Instant::now();
+        assert_eq!(parts.join(&payloads[0], now), None);
+        assert_eq!(parts.join(&split("{}")[0], now), Some("{}".to_string()));
+        assert_eq!(parts.messages.len(), 1);
+        let later = now + PARTS_TIMEOUT;
+        assert_eq!(parts.join(&payloads[1], later), None);
+        assert!(parts.messages.is_empty());
+    }
+}
@@ -1,4 +1,4 @@
-use super::schema::{belongings, cards, decks};
+use super::schema::{belongings, cards, decks, rooms};
 use serde::Serialize;
 
 #[derive(Identifiable, Queryable, Serialize)]
@@ -44,3 +44,17 @@ pub struct NewBelonging {
     pub card_id: i32,
     pub num: i32,
 }
+
+/// Room checkpoint saved to restore the room after restart
+#[derive(Queryable, Insertable, AsChangeset)]
+#[table_name = "rooms"]
+pub struct Room {
+    pub id: String,
+    /// instance hosting the room
+    pub instance: String,
+    pub name: String,
+    /// session ids of members
+    pub players: Vec<String>,
+    /// room state in JSON
+    pub checkpoint: String,
+}
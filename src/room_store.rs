@@ -0,0 +1,70 @@
+/**
+ * ルームの保存と復元
+ * サーバー再起動後もゲームを続けられるように rooms テーブルに保存する
+ */
+use crate::models::Room;
+use crate::schema::rooms;
+use crate::DbPool;
+use card_playroom_server::websocket::room_store::{RoomStore, SavedRoom};
+use diesel::prelude::*;
+use std::error::Error;
+use uuid::Uuid;
+
+/// Rooms saved in `rooms` table
+pub struct DbRoomStore {
+    pool: DbPool,
+}
+
+impl DbRoomStore {
+    pub fn new(pool: DbPool) -> DbRoomStore {
+        DbRoomStore { pool }
+    }
+}
+
+impl RoomStore for DbRoomStore {
+    fn save(&self, room: &SavedRoom) -> Result<(), Box<dyn Error>> {
+        let conn = self.pool.get()?;
+        let row = Room {
+            id: room.id.to_string(),
+            instance: room.instance.to_owned(),
+            name: room.name.to_owned(),
+            players: room.players.iter().map(Uuid::to_string).collect(),
+            checkpoint: room.checkpoint.to_owned(),
+        };
+        diesel::insert_into(rooms::table)
+            .values(&row)
+            .on_conflict(rooms::id)
+            .do_update()
+            .set(&row)
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn delete(&self, room_id: &Uuid) -> Result<(), Box<dyn Error>> {
+        let conn = self.pool.get()?;
+        diesel::delete(rooms::table.find(room_id.to_string())).execute(&conn)?;
+        Ok(())
+    }
+
+    fn load(&self, instance: &str) -> Result<Vec<SavedRoom>, Box<dyn Error>> {
+        let conn = self.pool.get()?;
+        let rows = rooms::table
+            .filter(rooms::instance.eq(instance))
+            .load::<Room>(&conn)?;
+        let mut saved_rooms = Vec::new();
+        for row in rows {
+            saved_rooms.push(SavedRoom {
+                id: Uuid::parse_str(&row.id)?,
+                instance: row.instance,
+                name: row.name,
+                players: row
+                    .players
+                    .iter()
+                    .map(|player| Uuid::parse_str(player))
+                    .collect::<Result<_, _>>()?,
+                checkpoint: row.checkpoint,
+            });
+        }
+        Ok(saved_rooms)
+    }
+}
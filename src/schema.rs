@@ -22,6 +22,16 @@ table! {
     }
 }
 
+table! {
+    rooms (id) {
+        id -> Varchar,
+        instance -> Varchar,
+        name -> Varchar,
+        players -> Array<Text>,
+        checkpoint -> Text,
+    }
+}
+
 joinable!(belongings -> cards (card_id));
 joinable!(belongings -> decks (deck_id));
 
@@ -29,4 +39,5 @@ allow_tables_to_appear_in_same_query!(
     belongings,
     cards,
     decks,
+    rooms,
 );
@@ -1,5 +1,4 @@
 use std::{
-    collections::HashSet,
     fmt::{Display, Formatter},
     time::{Duration, Instant},
 };
@@ -10,11 +9,28 @@ use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+mod board;
 mod codec;
+pub mod deck_store;
+mod error;
+mod lobby;
+mod matchmaking;
+pub mod message_bus;
+mod protocol;
+pub mod remote_room;
+mod room;
+pub mod room_actor;
 pub mod room_manager;
+pub mod room_store;
+mod shuffle;
 pub mod tcp_session;
 mod websocket_session;
 
+pub use board::{BoardCard, BoardState, CardMove};
+pub use error::{ErrorCode, ServerError};
+pub use protocol::{RequestId, WsRequest, WsResponse};
+pub use room::Room;
+
 /// Status list for websocket
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Status {
@@ -33,6 +49,12 @@ impl Display for Status {
 /// Event list for websocket
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Event {
+    /// event for protocol version negotiation
+    Hello,
+    /// event for session id and resume token
+    Session,
+    /// event for resuming disconnected session
+    Resume,
     /// event for creating room
     CreateRoom,
     /// event for entering room
@@ -41,10 +63,66 @@ pub enum Event {
     GetRoomList,
     /// event for someone entering room
     SomeoneEnterRoom,
+    /// event for leaving room
+    LeaveRoom,
+    /// event for someone leaving room
+    SomeoneLeaveRoom,
+    /// event for setting display name
+    SetName,
+    /// event for room members list
+    Roster,
+    /// event for new room host
+    HostChanged,
     /// event for receive first cards info
     FirstCardsInfo,
     /// event for receive cards info (not first)
     CardsInfo,
+    /// event for chat message in room
+    Chat,
+    /// event for recent chat messages of entered room
+    ChatHistory,
+    /// event for end of replayed room messages
+    Replay,
+    /// event for member kicked or banned by host
+    Kicked,
+    /// event for room locked or unlocked by host
+    LockRoom,
+    /// event for ready toggle of player
+    Ready,
+    /// event for room going back to lobby or into ready check
+    RoomState,
+    /// event for game start after every player is ready
+    StartGame,
+    /// event for game end
+    FinishGame,
+    /// event for subscribing lobby
+    Subscribe,
+    /// event for unsubscribing lobby
+    Unsubscribe,
+    /// event for room added to lobby
+    RoomAdded,
+    /// event for room in lobby changed
+    RoomUpdated,
+    /// event for room gone from lobby
+    RoomRemoved,
+    /// event for room closed by server
+    RoomClosed,
+    /// event for entering matchmaking queue
+    Matchmake,
+    /// event for leaving matchmaking queue
+    CancelMatchmaking,
+    /// event for match found, or matchmaking timed out
+    MatchFound,
+    /// event for every card on room board
+    BoardSnapshot,
+    /// event for cards moved on room board
+    MoveCards,
+    /// event for deck dealt to player
+    PlayDeck,
+    /// event for hash of game seed told at game start
+    SeedCommitted,
+    /// event for game seed revealed at game end
+    SeedRevealed,
     /// unexpected event
     Unknown,
 }
@@ -61,10 +139,13 @@ impl Display for Event {
 /// New chat session is created
 pub struct Connect {
     pub addr: Recipient<ChatMessage>,
+    /// told when the session is taken out of its room,
+    /// `None` if the session does not keep its room
+    pub left: Option<Recipient<RoomLeft>>,
 }
 
 impl actix::Message for Connect {
-    type Result = Uuid;
+    type Result = SessionInfo;
 }
 
 /// Session is disconnected
@@ -72,18 +153,40 @@ impl actix::Message for Connect {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: Uuid,
+    /// token the session holds, stale connections are ignored
+    pub resume_token: Uuid,
 }
 
-/// Send message to specific room
-#[derive(Message)]
-#[rtype(result = "()")]
+/// Take over disconnected session with its resume token
+pub struct Resume {
+    /// Id of the new client session
+    pub session_id: Uuid,
+    /// Token of the session to resume
+    pub resume_token: Uuid,
+}
+
+impl actix::Message for Resume {
+    type Result = Option<ResumedSession>;
+}
+
+/// Resumed session and its room
+pub struct ResumedSession {
+    pub info: SessionInfo,
+    pub room: Option<remote_room::RoomLink>,
+}
+
+/// Send cards info to room members, sent to room actor
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[rtype(result = "Result<(), ServerError>")]
 pub struct Message {
     /// Id of the client session
     pub id: Uuid,
-    /// Peer message
-    pub msg: String,
-    /// Room id
-    pub room: Uuid,
+    /// whether these are the first cards of the game
+    pub first: bool,
+    pub cards: CardInfoList,
+    /// sent by client older than room states, which can not get ready,
+    /// so its cards are taken whatever state room is in
+    pub legacy: bool,
 }
 
 /// List of available rooms
@@ -93,16 +196,157 @@ impl actix::Message for ListRooms {
     type Result = RoomInfoList;
 }
 
+/// Get room changes until unsubscribing or entering game
+pub struct Subscribe {
+    /// Client id
+    pub session_id: Uuid,
+    pub query: RoomQuery,
+}
+
+impl actix::Message for Subscribe {
+    type Result = Result<RoomInfoList, ServerError>;
+}
+
+/// Stop getting room changes
+pub struct Unsubscribe {
+    /// Client id
+    pub session_id: Uuid,
+}
+
+impl actix::Message for Unsubscribe {
+    type Result = Result<(), ServerError>;
+}
+
 /// Join room.
 pub struct Join {
     /// Client id
     pub session_id: Uuid,
     /// Room id
     pub room_id: Uuid,
+    /// Password of the room if it has one
+    pub password: Option<String>,
+    /// Join as spectator
+    pub spectate: bool,
 }
 
 impl actix::Message for Join {
-    type Result = RoomInfo;
+    type Result = Result<JoinedRoom, ServerError>;
+}
+
+/// Entered room and where it runs
+pub struct JoinedRoom {
+    pub info: RoomInfo,
+    pub addr: remote_room::RoomLink,
+}
+
+/// Join room by its invite code.
+pub struct JoinByCode {
+    /// Client id
+    pub session_id: Uuid,
+    pub invite_code: String,
+    /// Password of the room if it has one
+    pub password: Option<String>,
+    /// Join as spectator
+    pub spectate: bool,
+}
+
+impl actix::Message for JoinByCode {
+    type Result = Result<JoinedRoom, ServerError>;
+}
+
+/// Wait in matchmaking queue until another player is found
+pub struct Matchmake {
+    /// Client id
+    pub session_id: Uuid,
+    /// game or format to play, players are matched only with the same one
+    pub tag: Option<String>,
+}
+
+impl actix::Message for Matchmake {
+    type Result = Result<MatchTicket, ServerError>;
+}
+
+/// Place in matchmaking queue
+pub struct MatchTicket {
+    pub info: QueueInfo,
+    /// room both players joined, or error if no match is found in time.
+    /// Fails if session leaves queue
+    pub matched: futures::channel::oneshot::Receiver<Result<JoinedRoom, ServerError>>,
+}
+
+/// Leave matchmaking queue
+pub struct CancelMatchmaking {
+    /// Client id
+    pub session_id: Uuid,
+}
+
+impl actix::Message for CancelMatchmaking {
+    type Result = Result<QueueInfo, ServerError>;
+}
+
+/// Leave joined room.
+pub struct Leave {
+    /// Client id
+    pub session_id: Uuid,
+}
+
+impl actix::Message for Leave {
+    type Result = Result<RoomInfo, ServerError>;
+}
+
+/// Send chat message to joined room, sent to room actor or chat server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendChat {
+    /// Client id
+    pub session_id: Uuid,
+    pub text: String,
+}
+
+impl actix::Message for SendChat {
+    type Result = Result<ChatEntry, ServerError>;
+}
+
+/// Tell id client keeps across connections
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetClientId {
+    /// Client id
+    pub session_id: Uuid,
+    pub client_id: String,
+}
+
+/// Set display name of player
+pub struct SetName {
+    /// Client id
+    pub session_id: Uuid,
+    pub name: String,
+}
+
+impl actix::Message for SetName {
+    /// Name actually used in the joined room
+    type Result = Result<PlayerName, ServerError>;
+}
+
+/// Get room messages missed after `after`, sent to room actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Replay {
+    /// Client id
+    pub session_id: Uuid,
+    /// Seq of the last message client received
+    pub after: u64,
+}
+
+/// Missed room messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayedMessages {
+    pub messages: Vec<String>,
+    /// seq of the last room broadcast
+    pub last_seq: u64,
+}
+
+impl actix::Message for Replay {
+    /// `None` if messages are already dropped from room history
+    type Result = Option<ReplayedMessages>;
 }
 
 pub struct Create {
@@ -110,34 +354,185 @@ pub struct Create {
     pub session_id: Uuid,
     /// Room name
     pub room_name: String,
+    pub settings: RoomSettings,
+    /// Password required to join
+    pub password: Option<String>,
 }
 
-pub struct CreateRoom {
-    pub room_id: Uuid,
-    pub room_name: String,
+impl actix::Message for Create {
+    type Result = Result<JoinedRoom, ServerError>;
 }
 
-impl actix::Message for Create {
-    type Result = CreateRoom;
+/// Remove member from room, only host can, sent to room actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Kick {
+    /// Client id of host
+    pub session_id: Uuid,
+    /// Client id of member to remove
+    pub target: Uuid,
+    /// Keep the member from joining again, even from a new connection
+    pub ban: bool,
 }
 
-pub struct Session {
-    address: Recipient<ChatMessage>,
+impl actix::Message for Kick {
+    type Result = Result<KickInfo, ServerError>;
 }
 
-pub struct Room {
-    name: String,
-    members: HashSet<Uuid>,
+/// Mark player ready or not ready for next game, sent to room actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetReady {
+    /// Client id
+    pub session_id: Uuid,
+    pub ready: bool,
 }
 
-impl Room {
-    fn remove_member(&mut self, session_id: &Uuid) -> bool {
-        self.members.remove(session_id)
-    }
+impl actix::Message for SetReady {
+    type Result = Result<RosterEntry, ServerError>;
+}
 
-    fn is_empty(&self) -> bool {
-        self.members.is_empty()
-    }
+/// End game being played, only host can, sent to room actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FinishGame {
+    /// Client id of host
+    pub session_id: Uuid,
+}
+
+impl actix::Message for FinishGame {
+    type Result = Result<RoomInfo, ServerError>;
+}
+
+/// Move cards on board of joined room, sent to room actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MoveCards {
+    /// Client id
+    pub session_id: Uuid,
+    pub moves: Vec<CardMove>,
+}
+
+impl actix::Message for MoveCards {
+    /// Moved cards
+    type Result = Result<BoardState, ServerError>;
+}
+
+/// Deal stored deck to player, sent to room actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayDeck {
+    /// Client id
+    pub session_id: Uuid,
+    pub deck_id: i32,
+}
+
+impl actix::Message for PlayDeck {
+    /// Dealt cards
+    type Result = Result<BoardState, ServerError>;
+}
+
+/// Lock or unlock room against new members, only host can, sent to room actor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lock {
+    /// Client id of host
+    pub session_id: Uuid,
+    pub locked: bool,
+}
+
+impl actix::Message for Lock {
+    type Result = Result<RoomInfo, ServerError>;
+}
+
+/// Message for chat server and room actor communications
+/// Add session to room after checking it can join
+pub struct AddMember {
+    pub session_id: Uuid,
+    /// display name player asked for
+    pub name: Option<String>,
+    /// id client keeps across connections
+    pub client_id: Option<String>,
+    /// `None` while disconnected
+    pub address: Option<Recipient<ChatMessage>>,
+    /// saved with the room, so that session can resume after restart
+    pub resume_token: Uuid,
+    pub password: Option<room::JoinPassword>,
+    pub spectate: bool,
+}
+
+impl actix::Message for AddMember {
+    /// Room info for members
+    type Result = Result<RoomInfo, ServerError>;
+}
+
+/// Remove session from room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoveMember {
+    pub session_id: Uuid,
+}
+
+impl actix::Message for RemoveMember {
+    /// `None` if session is not a member
+    type Result = Option<RoomInfo>;
+}
+
+/// Change display name of member
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenameMember {
+    pub session_id: Uuid,
+    pub name: String,
+}
+
+impl actix::Message for RenameMember {
+    /// Name actually used in the room, `None` if session is not a member
+    type Result = Option<String>;
+}
+
+/// Member is disconnected (`None`) or resumed
+pub struct MemberAddress {
+    pub session_id: Uuid,
+    pub address: Option<Recipient<ChatMessage>>,
+    pub resume_token: Uuid,
+}
+
+impl actix::Message for MemberAddress {
+    /// Room info for members, `None` if session is not a member
+    type Result = Option<RoomInfo>;
+}
+
+/// Room info lobby shows is changed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomChanged {
+    pub room_id: Uuid,
+    pub info: RoomInfo,
+}
+
+/// Member is removed by room host
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MemberRemoved {
+    pub room_id: Uuid,
+    pub session_id: Uuid,
+}
+
+/// Room actor is stopped, its members are no longer in it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomStopped {
+    pub room_id: Uuid,
+}
+
+pub struct Session {
+    /// `None` while disconnected and waiting for resume
+    address: Option<Recipient<ChatMessage>>,
+    /// told when the session is taken out of its room
+    left: Option<Recipient<RoomLeft>>,
+    resume_token: Uuid,
+    disconnected_at: Option<Instant>,
+    /// joined room
+    room: Option<Uuid>,
+    /// display name player asked for
+    name: Option<String>,
+    /// id client keeps across connections, told on hello
+    client_id: Option<String>,
+    /// `None` unless subscribed to lobby
+    lobby: Option<lobby::Subscription>,
 }
 
 /// How often heartbeat pings are sent
@@ -150,6 +545,14 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 #[rtype(result = "()")]
 pub struct ChatMessage(pub String);
 
+/// Session is taken out of room without asking, e.g. kicked or room closed.
+/// Chat server sends this to session, so that it drops its link to the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomLeft {
+    pub room_id: Uuid,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CardPosition {
     x: f32,
@@ -163,6 +566,9 @@ pub struct CardInfo {
     pub index: i32,
     pub own: bool,
     pub position: CardPosition,
+    /// face is shown only to owner, e.g. cards in hand
+    #[serde(default)]
+    pub private: bool,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CardInfoList {
@@ -173,11 +579,99 @@ pub struct CardInfoList {
 pub struct RoomInfo {
     pub id: Uuid,
     pub name: String,
+    /// number of players
     pub num: usize,
+    /// number of spectators, not counted in `num`
+    #[serde(default)]
+    pub spectators: usize,
+    pub settings: RoomSettings,
+    /// whether password is required to join
+    pub has_password: bool,
+    /// code to invite players, only told to members
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite_code: Option<String>,
+    /// whether new members are refused
+    #[serde(default)]
+    pub locked: bool,
+    pub state: RoomState,
+    /// seq of the last room broadcast
+    pub last_seq: u64,
 }
+/// Where room is in its game cycle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomState {
+    /// waiting for players
+    Lobby,
+    /// some players are ready
+    ReadyCheck,
+    /// game is being played, card events are accepted
+    Playing,
+    /// game is over, players can get ready for next one
+    Finished,
+}
+
+/// Settings chosen on room creation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomSettings {
+    /// how many players can join
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,
+    /// whether non-playing members can watch
+    #[serde(default)]
+    pub allow_spectators: bool,
+    /// hidden from room list, joinable only by invite code
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub game_title: Option<String>,
+    /// cards come only from stored decks, cards info from clients is refused
+    #[serde(default)]
+    pub decks_only: bool,
+}
+
+fn default_max_players() -> usize {
+    2
+}
+
+impl Default for RoomSettings {
+    fn default() -> RoomSettings {
+        RoomSettings {
+            max_players: default_max_players(),
+            allow_spectators: false,
+            private: false,
+            game_title: None,
+            decks_only: false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RoomInfoList {
     pub rooms: Vec<RoomInfo>,
+    /// number of matching rooms, including ones not in this page
+    #[serde(default)]
+    pub total: usize,
+}
+
+/// Filter and page of lobby rooms
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoomQuery {
+    /// part of room name, case insensitive
+    #[serde(default)]
+    pub name: Option<String>,
+    /// game title, case insensitive
+    #[serde(default)]
+    pub game_title: Option<String>,
+    /// only rooms which are neither full nor locked
+    #[serde(default)]
+    pub joinable: bool,
+    /// rooms to skip
+    #[serde(default)]
+    pub offset: usize,
+    /// rooms per page
+    #[serde(default)]
+    pub limit: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -185,55 +679,115 @@ pub struct SimpleMessage {
     pub message: String,
 }
 
+/// Chat message in room
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct WsMessage<T> {
-    data: T,
-    event: Event,
-    status: Status,
-}
-
-impl RoomInfo {
-    pub fn get_json_data(&self, status: Status, event: Event) -> String {
-        serde_json::to_string(&WsMessage {
-            data: self.clone(),
-            event,
-            status,
-        })
-        .unwrap()
-    }
+pub struct ChatEntry {
+    pub session_id: Uuid,
+    /// display name of sender
+    pub name: String,
+    pub text: String,
+    /// unix time in milliseconds
+    pub timestamp: u64,
 }
 
-impl RoomInfoList {
-    pub fn get_json_data(&self, status: Status, event: Event) -> String {
-        serde_json::to_string(&WsMessage {
-            data: self.clone().rooms,
-            event,
-            status,
-        })
-        .unwrap()
-    }
+/// Display name of player
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerName {
+    pub name: String,
 }
 
-impl CardInfoList {
-    pub fn get_json_data(&self, status: Status, event: Event) -> String {
-        serde_json::to_string(&WsMessage {
-            data: self.clone().cards,
-            event,
-            status,
-        })
-        .unwrap()
-    }
+/// Room member seen by other members
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RosterEntry {
+    pub session_id: Uuid,
+    pub name: String,
+    /// `None` for spectators
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seat: Option<usize>,
+    #[serde(default)]
+    pub spectator: bool,
+    pub host: bool,
+    /// whether player is ready for next game
+    #[serde(default)]
+    pub ready: bool,
 }
 
-impl SimpleMessage {
-    pub fn get_json_data(&self, status: Status, event: Event) -> String {
-        serde_json::to_string(&WsMessage {
-            data: self.clone(),
-            event,
-            status,
-        })
-        .unwrap()
-    }
+/// Members of room in join order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomRoster {
+    pub room_id: Uuid,
+    pub members: Vec<RosterEntry>,
+}
+
+/// Member removed from room by host
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KickInfo {
+    pub room_id: Uuid,
+    /// removed member
+    pub session_id: Uuid,
+    /// whether the member can not join again
+    pub banned: bool,
+}
+
+/// Room closed by server, members are removed from it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomClosed {
+    pub room_id: Uuid,
+    /// why room is closed
+    pub reason: String,
+}
+
+/// Session waiting in matchmaking queue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueInfo {
+    /// game or format asked for
+    pub tag: Option<String>,
+    /// number of sessions waiting for the tag, including this one
+    pub waiting: usize,
+    /// seconds to wait before giving up
+    pub timeout: u64,
+}
+
+/// Seed every shuffle of a game is derived from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameSeed {
+    pub room_id: Uuid,
+    /// SHA-256 of seed in hex
+    pub commitment: String,
+    /// seed in hex, `None` until game ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<String>,
+}
+
+/// Replay is finished
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayInfo {
+    /// seq of the last room broadcast
+    pub last_seq: u64,
+}
+
+/// Session id and token to resume it after disconnection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub session_id: Uuid,
+    pub resume_token: Uuid,
+    /// joined room
+    pub room: Option<RoomInfo>,
+}
+
+/// Negotiated protocol version and what the server supports
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProtocolInfo {
+    pub version: u32,
+    pub supported_versions: Vec<u32>,
+    pub capabilities: Vec<String>,
+}
+
+/// Query parameters of websocket route
+#[derive(Deserialize, Debug)]
+struct ConnectQuery {
+    /// protocol version client speaks
+    version: Option<u32>,
 }
 
 /// Entry point for our websocket route
@@ -241,21 +795,32 @@ async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<room_manager::ChatServer>>,
+    query: web::Query<ConnectQuery>,
     // db_pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
     // print request headers
     for x in req.headers().iter() {
         println!("{:?}", x);
     }
+    // clients without version are treated as the oldest supported one
+    let version = match query.version {
+        Some(requested) => match protocol::negotiate_version(requested) {
+            Some(version) => version,
+            None => {
+                return Ok(HttpResponse::BadRequest()
+                    .content_type("application/json")
+                    .body(protocol::unsupported_version(requested).to_json()))
+            }
+        },
+        None => protocol::MIN_PROTOCOL_VERSION,
+    };
     // start websocket
     ws::start(
-        websocket_session::WsChatSession {
-            id: Uuid::new_v4(),
-            hb: Instant::now(),
-            room: None, // defaultルームへの割り当てなし
-            name: None,
-            addr: srv.get_ref().clone(),
-        },
+        websocket_session::WsChatSession::new(
+            srv.get_ref().clone(),
+            version,
+            query.version.is_some(),
+        ),
         &req,
         stream,
     )
@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::deck_store::DeckCard;
+use super::shuffle;
+use super::*;
+
+/// Card id members are told for cards whose face is hidden from them,
+/// database ids start from 1
+pub const HIDDEN_CARD_ID: i32 = 0;
+
+/// Card on the table as room keeps it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BoardCard {
+    /// unique among cards on the board, copies of a card differ in it
+    pub instance_id: u32,
+    /// id of the card in database, `HIDDEN_CARD_ID` for members face is hidden from
+    pub card_id: i32,
+    pub face: String,
+    pub back: String,
+    /// player owning the card, `None` for cards anyone can move
+    pub owner: Option<Uuid>,
+    pub position: CardPosition,
+    /// order among cards in the same place
+    pub index: i32,
+    /// face is shown only to owner, e.g. cards in hand
+    pub private: bool,
+    /// face is hidden from everyone including owner, e.g. cards in deck
+    pub face_down: bool,
+}
+
+impl BoardCard {
+    /// Whether face is hidden from `viewer`, `None` for members owning no cards
+    fn is_hidden_from(&self, viewer: Option<&Uuid>) -> bool {
+        self.face_down || (self.private && self.owner.as_ref() != viewer)
+    }
+}
+
+/// Move of card asked by player
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CardMove {
+    pub instance_id: u32,
+    pub position: CardPosition,
+    #[serde(default)]
+    pub index: Option<i32>,
+    /// show face only to owner or to every member
+    #[serde(default)]
+    pub private: Option<bool>,
+    /// turn card face down or face up
+    #[serde(default)]
+    pub face_down: Option<bool>,
+}
+
+/// Cards of room board
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BoardState {
+    pub room_id: Uuid,
+    /// bumped on every change of board
+    pub version: u64,
+    /// every card for snapshot, moved ones for moves
+    pub cards: Vec<BoardCard>,
+}
+
+impl BoardState {
+    /// Same state as `viewer` sees it, with backs in place of faces hidden
+    /// from the viewer and their card ids hidden too. `None` if nothing is hidden
+    pub fn for_viewer(&self, viewer: Option<&Uuid>) -> Option<BoardState> {
+        if !self.cards.iter().any(|card| card.is_hidden_from(viewer)) {
+            return None;
+        }
+        Some(BoardState {
+            cards: self
+                .cards
+                .iter()
+                .map(|card| {
+                    if card.is_hidden_from(viewer) {
+                        BoardCard {
+                            card_id: HIDDEN_CARD_ID,
+                            face: card.back.clone(),
+                            ..card.clone()
+                        }
+                    } else {
+                        card.clone()
+                    }
+                })
+                .collect(),
+            ..self.clone()
+        })
+    }
+
+    /// Owners of private cards, who see them unlike other members
+    pub fn private_owners(&self) -> HashSet<Uuid> {
+        self.cards
+            .iter()
+            .filter(|card| card.private)
+            .filter_map(|card| card.owner)
+            .collect()
+    }
+}
+
+/// Canonical cards and positions of a room, clients only ask to move them
+#[derive(Serialize, Deserialize, Default)]
+pub struct Board {
+    cards: Vec<BoardCard>,
+    /// deck each player is dealt in this game
+    decks: HashMap<Uuid, i32>,
+    version: u64,
+    /// instance id of the next card put on board
+    next_instance: u32,
+}
+
+impl Board {
+    pub fn state(&self, room_id: Uuid) -> BoardState {
+        BoardState {
+            room_id,
+            version: self.version,
+            cards: self.cards.clone(),
+        }
+    }
+
+    /// Remove every card, e.g. for new game
+    pub fn clear(&mut self) {
+        self.cards.clear();
+        self.decks.clear();
+        self.version += 1;
+    }
+
+    /// Put card on board, instance id is given by board
+    pub fn put(&mut self, card: BoardCard) {
+        self.cards.push(BoardCard {
+            instance_id: self.next_instance,
+            ..card
+        });
+        self.next_instance += 1;
+        self.version += 1;
+    }
+
+    /// Replace cards of player with every copy of deck cards, shuffled
+    /// and stacked in one face down pile nobody sees faces of. Returns dealt cards.
+    /// Copies are sorted by card id before `shuffle::shuffle`, so that the
+    /// order depends only on deck and `rng`
+    pub fn deal_deck<R: RngCore>(
+        &mut self,
+        owner: Uuid,
+        deck_id: i32,
+        deck: &[DeckCard],
+        rng: &mut R,
+    ) -> Vec<BoardCard> {
+        self.cards.retain(|card| card.owner != Some(owner));
+        self.decks.insert(owner, deck_id);
+        let first = self.cards.len();
+        let mut copies: Vec<&DeckCard> = deck
+            .iter()
+            .flat_map(|card| (0..card.num).map(move |_| card))
+            .collect();
+        copies.sort_by_key(|card| card.card_id);
+        shuffle::shuffle(&mut copies, rng);
+        for (index, card) in copies.into_iter().enumerate() {
+            self.put(BoardCard {
+                instance_id: 0,
+                card_id: card.card_id,
+                face: card.face.clone(),
+                back: card.back.clone(),
+                owner: Some(owner),
+                position: CardPosition { x: 0.0, y: 0.0 },
+                index: index as i32,
+                private: true,
+                face_down: true,
+            });
+        }
+        self.cards[first..].to_vec()
+    }
+
+    /// Deck the player is dealt in this game
+    pub fn deck_of(&self, player: &Uuid) -> Option<i32> {
+        self.decks.get(player).copied()
+    }
+
+    /// Replace cards of player with the ones sent by old style first cards
+    pub fn deal(&mut self, owner: Uuid, cards: &[CardInfo]) {
+        self.cards.retain(|card| card.owner != Some(owner));
+        for card in cards {
+            self.put(from_card_info(owner, card));
+        }
+    }
+
+    /// Apply cards sent by old style cards info, matched by card id and index
+    pub fn update(&mut self, owner: Uuid, cards: &[CardInfo]) {
+        for info in cards {
+            match self.cards.iter_mut().find(|card| {
+                card.owner == Some(owner) && card.card_id == info.id && card.index == info.index
+            }) {
+                Some(card) => {
+                    card.position = info.position.clone();
+                    card.private = info.private;
+                    self.version += 1;
+                }
+                None => self.put(from_card_info(owner, info)),
+            }
+        }
+    }
+
+    /// Move cards for player, all or none of them.
+    /// Returns moved cards, or instance id of card player can not move
+    pub fn apply(&mut self, player: &Uuid, moves: &[CardMove]) -> Result<Vec<BoardCard>, u32> {
+        for card_move in moves {
+            match self.find(card_move.instance_id) {
+                Some(card) if card.owner.is_none() || card.owner == Some(*player) => (),
+                _ => return Err(card_move.instance_id),
+            }
+        }
+        let mut moved = Vec::with_capacity(moves.len());
+        for card_move in moves {
+            let card = self
+                .cards
+                .iter_mut()
+                .find(|card| card.instance_id == card_move.instance_id)
+                .unwrap();
+            card.position = card_move.position.clone();
+            if let Some(index) = card_move.index {
+                card.index = index;
+            }
+            if let Some(private) = card_move.private {
+                card.private = private;
+            }
+            if let Some(face_down) = card_move.face_down {
+                card.face_down = face_down;
+            }
+            moved.push(card.clone());
+        }
+        self.version += 1;
+        Ok(moved)
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn find(&self, instance_id: u32) -> Option<&BoardCard> {
+        self.cards
+            .iter()
+            .find(|card| card.instance_id == instance_id)
+    }
+}
+
+fn from_card_info(owner: Uuid, info: &CardInfo) -> BoardCard {
+    BoardCard {
+        instance_id: 0,
+        card_id: info.id,
+        face: info.face.clone(),
+        back: info.back.clone(),
+        owner: Some(owner),
+        position: info.position.clone(),
+        index: info.index,
+        private: info.private,
+        face_down: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(owner: Option<Uuid>) -> BoardCard {
+        BoardCard {
+            instance_id: 0,
+            card_id: 1,
+            face: "face.png".to_string(),
+            back: "back.png".to_string(),
+            owner,
+            position: CardPosition { x: 0.0, y: 0.0 },
+            index: 0,
+            private: false,
+            face_down: false,
+        }
+    }
+
+    fn to(instance_id: u32, x: f32) -> CardMove {
+        CardMove {
+            instance_id,
+            position: CardPosition { x, y: 0.0 },
+            index: None,
+            private: None,
+            face_down: None,
+        }
+    }
+
+    #[test]
+    fn apply_moves_own_and_shared_cards() {
+        let player = Uuid::new_v4();
+        let mut board = Board::default();
+        board.put(card(Some(player)));
+        board.put(card(None));
+        let version = board.version();
+        let turned = CardMove {
+            index: Some(3),
+            face_down: Some(true),
+            ..to(1, 2.0)
+        };
+        let moved = board.apply(&player, &[to(0, 1.0), turned]).unwrap();
+        assert_eq!(moved.len(), 2);
+        assert_eq!(board.version(), version + 1);
+        let shared = board.find(1).unwrap();
+        assert_eq!(shared.position.x, 2.0);
+        assert_eq!(shared.index, 3);
+        assert!(shared.face_down);
+        assert!(!shared.private);
+    }
+
+    #[test]
+    fn apply_moves_nothing_if_any_card_is_not_movable() {
+        let (player, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut board = Board::default();
+        board.put(card(Some(player)));
+        board.put(card(Some(other)));
+        let version = board.version();
+        assert_eq!(
+            board.apply(&player, &[to(0, 1.0), to(1, 1.0)]).err(),
+            Some(1)
+        );
+        assert_eq!(
+            board.apply(&player, &[to(0, 1.0), to(7, 1.0)]).err(),
+            Some(7)
+        );
+        assert_eq!(board.find(0).unwrap().position.x, 0.0);
+        assert_eq!(board.version(), version);
+    }
+
+    #[test]
+    fn faces_are_hidden_by_viewer() {
+        let (player, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut board = Board::default();
+        board.put(BoardCard {
+            private: true,
+            ..card(Some(player))
+        });
+        board.put(BoardCard {
+            face_down: true,
+            ..card(Some(player))
+        });
+        let state = board.state(Uuid::new_v4());
+        let seen = |viewer: &Uuid| -> Vec<(i32, String)> {
+            let state = state
+                .for_viewer(Some(viewer))
+                .unwrap_or_else(|| state.clone());
+            state
+                .cards
+                .into_iter()
+                .map(|card| (card.card_id, card.face))
+                .collect()
+        };
+        let hidden = (HIDDEN_CARD_ID, "back.png".to_string());
+        assert_eq!(seen(&player), [(1, "face.png".to_string()), hidden.clone()]);
+        assert_eq!(seen(&other), [hidden.clone(), hidden]);
+        assert_eq!(
+            state.private_owners().into_iter().collect::<Vec<_>>(),
+            [player]
+        );
+    }
+}
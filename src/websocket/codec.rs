@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use serde_json as json;
 use uuid::Uuid;
 
-use super::RoomInfoList;
+use super::{RoomInfoList, ServerError};
 
 /// Client request
 #[derive(Serialize, Deserialize, Debug, Message)]
@@ -41,6 +41,9 @@ pub enum ChatResponse {
 
     /// Message
     Message(String),
+
+    /// Request failed
+    Error(ServerError),
 }
 
 /// Codec for Client -> Server transport
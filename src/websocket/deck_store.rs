@@ -0,0 +1,60 @@
+use std::error::Error;
+
+use super::{ErrorCode, ServerError};
+
+/// Most cards a deck can deal, counting every copy
+pub const MAX_DECK_SIZE: usize = 500;
+
+/// Card of stored deck
+pub struct DeckCard {
+    pub card_id: i32,
+    pub face: String,
+    pub back: String,
+    /// copies of the card in deck
+    pub num: usize,
+}
+
+/// Where decks are read from, so that players play only cards of their deck
+pub trait DeckStore: Send + Sync {
+    /// Cards of deck in stored order, `None` if deck does not exist
+    fn load(&self, deck_id: i32) -> Result<Option<Vec<DeckCard>>, Box<dyn Error>>;
+}
+
+/// Check deck is small enough to deal, copies of each card are set by users
+pub fn check_deck_size(deck_id: i32, deck: &[DeckCard]) -> Result<(), ServerError> {
+    let size = deck
+        .iter()
+        .fold(0usize, |size, card| size.saturating_add(card.num));
+    if size > MAX_DECK_SIZE {
+        return Err(ServerError::new(
+            ErrorCode::DeckTooLarge,
+            &format!(
+                "deck {} has {} cards, at most {} can be dealt",
+                deck_id, size, MAX_DECK_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(num: usize) -> DeckCard {
+        DeckCard {
+            card_id: 1,
+            face: "face.png".to_string(),
+            back: "back.png".to_string(),
+            num,
+        }
+    }
+
+    #[test]
+    fn deck_size_counts_every_copy() {
+        assert!(check_deck_size(1, &[card(MAX_DECK_SIZE - 1), card(1)]).is_ok());
+        let error = check_deck_size(1, &[card(MAX_DECK_SIZE), card(1)]).unwrap_err();
+        assert_eq!(error.code, ErrorCode::DeckTooLarge);
+        assert!(check_deck_size(1, &[card(usize::MAX), card(usize::MAX)]).is_err());
+    }
+}
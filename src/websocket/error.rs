@@ -0,0 +1,104 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Stable error code clients can match on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// command is not known
+    UnknownCommand,
+    /// request is not valid json or lacks required data
+    MalformedPayload,
+    /// protocol version is too old
+    UnsupportedVersion,
+    /// resume token is invalid or grace period has passed
+    SessionExpired,
+    /// display name is empty, too long or has control characters
+    InvalidName,
+    /// chat message is empty or too long
+    InvalidChat,
+    /// room settings are out of range
+    InvalidSettings,
+    /// room does not exist
+    RoomNotFound,
+    /// room has no space for another member
+    RoomFull,
+    /// password of room is missing or wrong
+    WrongPassword,
+    /// room does not allow spectators
+    SpectatorsNotAllowed,
+    /// request is only for players, not spectators
+    NotPlayer,
+    /// request is only for room host
+    NotHost,
+    /// session is not a member of the room
+    MemberNotFound,
+    /// session can not be target of its own request, e.g. host kicking itself
+    InvalidTarget,
+    /// session is banned from the room
+    Banned,
+    /// room does not accept new members
+    RoomLocked,
+    /// request is not allowed in current room state
+    InvalidState,
+    /// request needs joined room
+    NotInRoom,
+    /// missed messages are already dropped from room history
+    ReplayUnavailable,
+    /// session is not waiting in matchmaking queue
+    NotQueued,
+    /// no other player is found within matchmaking timeout
+    MatchmakingTimeout,
+    /// card is not on board or belongs to another player
+    InvalidMove,
+    /// deck does not exist or has no cards
+    DeckNotFound,
+    /// deck has more cards than can be dealt
+    DeckTooLarge,
+    /// something is wrong with server
+    Internal,
+}
+
+/// Error sent to clients with `Status::Error`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    /// human readable message
+    pub message: String,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: &str) -> ServerError {
+        ServerError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn internal() -> ServerError {
+        ServerError::new(ErrorCode::Internal, "something is wrong with server")
+    }
+
+    pub fn not_in_room() -> ServerError {
+        ServerError::new(ErrorCode::NotInRoom, "not in room")
+    }
+
+    pub fn room_full(room_id: &Uuid) -> ServerError {
+        ServerError::new(ErrorCode::RoomFull, &format!("room {} is full", room_id))
+    }
+
+    pub fn room_not_found(room_id: &Uuid) -> ServerError {
+        ServerError::new(
+            ErrorCode::RoomNotFound,
+            &format!("room {} is not found", room_id),
+        )
+    }
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
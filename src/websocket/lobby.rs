@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use super::*;
+
+/// Rooms per page when client does not ask
+const DEFAULT_PAGE_SIZE: usize = 20;
+/// Most rooms per page
+const MAX_PAGE_SIZE: usize = 100;
+
+impl RoomQuery {
+    pub fn matches(&self, room: &RoomInfo) -> bool {
+        let name_matches = match &self.name {
+            Some(name) => room.name.to_lowercase().contains(&name.to_lowercase()),
+            None => true,
+        };
+        let title_matches = match &self.game_title {
+            Some(title) => {
+                room.settings.game_title.as_ref().map(|t| t.to_lowercase())
+                    == Some(title.to_lowercase())
+            }
+            None => true,
+        };
+        let joinable = !room.locked && room.num < room.settings.max_players;
+        name_matches && title_matches && (joinable || !self.joinable)
+    }
+
+    fn page_size(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+}
+
+/// Lobby subscription of session, remembers rooms already told
+/// so that only changes are sent after
+pub struct Subscription {
+    query: RoomQuery,
+    rooms: HashMap<Uuid, RoomInfo>,
+}
+
+impl Subscription {
+    pub fn new(query: RoomQuery) -> Subscription {
+        Subscription {
+            query,
+            rooms: HashMap::new(),
+        }
+    }
+
+    /// Page of matching rooms ordered by name.
+    /// Every matching room is watched for changes, not only the page
+    pub fn page(&mut self, rooms: &[RoomInfo]) -> RoomInfoList {
+        let mut matched: Vec<&RoomInfo> = rooms
+            .iter()
+            .filter(|room| self.query.matches(room))
+            .collect();
+        matched.sort_by_key(|room| (room.name.to_lowercase(), room.id));
+        self.rooms = matched
+            .iter()
+            .map(|room| (room.id, (*room).clone()))
+            .collect();
+        RoomInfoList {
+            total: matched.len(),
+            rooms: matched
+                .into_iter()
+                .skip(self.query.offset)
+                .take(self.query.page_size())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Events for matching rooms added, updated or removed since last told
+    pub fn update(&mut self, rooms: &[RoomInfo]) -> Vec<WsResponse> {
+        let matched: HashMap<Uuid, &RoomInfo> = rooms
+            .iter()
+            .filter(|room| self.query.matches(room))
+            .map(|room| (room.id, room))
+            .collect();
+        let mut events: Vec<WsResponse> = self
+            .rooms
+            .values()
+            .filter(|room| !matched.contains_key(&room.id))
+            .map(|room| WsResponse::RoomRemoved(room.clone()))
+            .collect();
+        for (room_id, room) in &matched {
+            match self.rooms.get(room_id) {
+                None => events.push(WsResponse::RoomAdded((*room).clone())),
+                Some(told) if listing_changed(told, room) => {
+                    events.push(WsResponse::RoomUpdated((*room).clone()))
+                }
+                _ => (),
+            }
+        }
+        self.rooms = matched
+            .into_iter()
+            .map(|(room_id, room)| (room_id, room.clone()))
+            .collect();
+        events
+    }
+}
+
+/// Whether lobby needs to know, seq of room broadcasts does not count
+fn listing_changed(told: &RoomInfo, room: &RoomInfo) -> bool {
+    told.name != room.name
+        || told.num != room.num
+        || told.spectators != room.spectators
+        || told.settings != room.settings
+        || told.has_password != room.has_password
+        || told.locked != room.locked
+        || told.state != room.state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(name: &str, num: usize) -> RoomInfo {
+        RoomInfo {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            num,
+            spectators: 0,
+            settings: RoomSettings::default(),
+            has_password: false,
+            invite_code: None,
+            locked: false,
+            state: RoomState::Lobby,
+            last_seq: 0,
+        }
+    }
+
+    fn told(events: &[WsResponse]) -> Vec<String> {
+        let mut told: Vec<String> = events
+            .iter()
+            .map(|event| match event {
+                WsResponse::RoomAdded(room) => format!("added {}", room.name),
+                WsResponse::RoomUpdated(room) => format!("updated {}", room.name),
+                WsResponse::RoomRemoved(room) => format!("removed {}", room.name),
+                _ => "other".to_string(),
+            })
+            .collect();
+        told.sort();
+        told
+    }
+
+    #[test]
+    fn update_tells_only_changes() {
+        let (first, second) = (room("first", 1), room("second", 1));
+        let mut subscription = Subscription::new(RoomQuery::default());
+        let grown = RoomInfo {
+            num: 2,
+            ..first.clone()
+        };
+        subscription.page(&[first]);
+        assert_eq!(
+            told(&subscription.update(&[grown.clone(), second.clone()])),
+            ["added second", "updated first"]
+        );
+        assert!(subscription
+            .update(&[grown.clone(), second.clone()])
+            .is_empty());
+        assert_eq!(told(&subscription.update(&[second])), ["removed first"]);
+    }
+
+    #[test]
+    fn update_ignores_seq() {
+        let first = room("first", 1);
+        let mut subscription = Subscription::new(RoomQuery::default());
+        let chatted = RoomInfo {
+            last_seq: 10,
+            ..first.clone()
+        };
+        subscription.page(&[first]);
+        assert!(subscription.update(&[chatted]).is_empty());
+    }
+
+    #[test]
+    fn update_removes_rooms_no_longer_matching() {
+        let first = room("first", 1);
+        let mut subscription = Subscription::new(RoomQuery {
+            joinable: true,
+            ..RoomQuery::default()
+        });
+        let full = [RoomInfo {
+            num: 2,
+            ..first.clone()
+        }];
+        subscription.page(&[first]);
+        assert_eq!(told(&subscription.update(&full)), ["removed first"]);
+        assert!(subscription.update(&full).is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use futures::channel::oneshot;
+use uuid::Uuid;
+
+use super::*;
+
+/// Name of rooms started for matched players
+pub const MATCH_ROOM_NAME: &str = "Quick match";
+
+/// Session waiting for another player
+pub struct Entry {
+    pub session_id: Uuid,
+    /// tells the session its room once matched
+    pub matched: oneshot::Sender<Result<JoinedRoom, ServerError>>,
+    /// game or format asked for, players are matched only with the same one
+    pub tag: Option<String>,
+    /// tells the entry from later ones of the same session
+    ticket: Uuid,
+}
+
+/// Settings of room started for players matched for `tag`
+pub fn room_settings(tag: &Option<String>) -> RoomSettings {
+    RoomSettings {
+        max_players: 2,
+        game_title: tag.clone(),
+        // only matched players join
+        private: true,
+        ..RoomSettings::default()
+    }
+}
+
+impl Entry {
+    fn same_tag(&self, tag: &Option<String>) -> bool {
+        self.tag.as_ref().map(|tag| tag.to_lowercase()) == tag.as_ref().map(|t| t.to_lowercase())
+    }
+}
+
+/// Sessions waiting for match, in the order they entered
+pub struct Queue {
+    entries: Vec<Entry>,
+    /// how long sessions wait before giving up
+    timeout: Duration,
+}
+
+impl Queue {
+    pub fn new(timeout: Duration) -> Queue {
+        Queue {
+            entries: Vec::new(),
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Add session, replacing the entry it already has.
+    /// Returns ticket to expire the entry with and what the session is told
+    pub fn enter(
+        &mut self,
+        session_id: Uuid,
+        tag: Option<String>,
+        matched: oneshot::Sender<Result<JoinedRoom, ServerError>>,
+    ) -> (Uuid, QueueInfo) {
+        self.leave(&session_id);
+        let ticket = Uuid::new_v4();
+        let entry = Entry {
+            session_id,
+            matched,
+            tag: tag.clone(),
+            ticket,
+        };
+        self.entries.push(entry);
+        (ticket, self.info(&tag))
+    }
+
+    /// Remove session from queue
+    pub fn leave(&mut self, session_id: &Uuid) -> Option<Entry> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.session_id == *session_id)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Remove entry of `ticket` if it is still waiting
+    pub fn expire(&mut self, ticket: &Uuid) -> Option<Entry> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.ticket == *ticket)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Take the two sessions waiting longest for the same tag as `session_id`
+    pub fn pair(&mut self, session_id: &Uuid) -> Option<(Entry, Entry)> {
+        // sessions whose connection is gone are not matched
+        self.entries.retain(|entry| !entry.matched.is_canceled());
+        let tag = self
+            .entries
+            .iter()
+            .find(|entry| entry.session_id == *session_id)?
+            .tag
+            .clone();
+        let mut indices = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.same_tag(&tag))
+            .map(|(index, _)| index);
+        let (first, second) = (indices.next()?, indices.next()?);
+        let second = self.entries.remove(second);
+        let first = self.entries.remove(first);
+        Some((first, second))
+    }
+
+    /// What sessions waiting for `tag` are told
+    pub fn info(&self, tag: &Option<String>) -> QueueInfo {
+        QueueInfo {
+            tag: tag.clone(),
+            waiting: self
+                .entries
+                .iter()
+                .filter(|entry| entry.same_tag(tag))
+                .count(),
+            timeout: self.timeout.as_secs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Receiver = oneshot::Receiver<Result<JoinedRoom, ServerError>>;
+
+    fn enter(queue: &mut Queue, tag: Option<&str>) -> (Uuid, Uuid, Receiver) {
+        let session_id = Uuid::new_v4();
+        let (matched, receiver) = oneshot::channel();
+        let (ticket, _) = queue.enter(session_id, tag.map(str::to_string), matched);
+        (session_id, ticket, receiver)
+    }
+
+    fn queue() -> Queue {
+        Queue::new(Duration::from_secs(60))
+    }
+
+    #[test]
+    fn pair_takes_longest_waiting_with_same_tag() {
+        let mut queue = queue();
+        let (first, _, _first) = enter(&mut queue, Some("poker"));
+        let (other, _, _other) = enter(&mut queue, None);
+        let (second, _, _second) = enter(&mut queue, Some("Poker"));
+        let (third, _, _third) = enter(&mut queue, Some("poker"));
+        let (a, b) = queue.pair(&third).unwrap();
+        assert_eq!((a.session_id, b.session_id), (first, second));
+        assert_eq!(queue.info(&Some("poker".to_string())).waiting, 1);
+        assert_eq!(queue.info(&None).waiting, 1);
+        assert!(queue.pair(&other).is_none());
+    }
+
+    #[test]
+    fn pair_needs_queued_session() {
+        let mut queue = queue();
+        let (_, _, _first) = enter(&mut queue, None);
+        assert!(queue.pair(&Uuid::new_v4()).is_none());
+        let (second, _, _second) = enter(&mut queue, None);
+        assert!(queue.pair(&second).is_some());
+    }
+
+    #[test]
+    fn pair_skips_disconnected_sessions() {
+        let mut queue = queue();
+        let (_, _, gone) = enter(&mut queue, None);
+        drop(gone);
+        let (second, _, _second) = enter(&mut queue, None);
+        assert!(queue.pair(&second).is_none());
+        assert_eq!(queue.info(&None).waiting, 1);
+    }
+
+    #[test]
+    fn entering_again_replaces_entry() {
+        let mut queue = queue();
+        let session_id = Uuid::new_v4();
+        let (matched, _old) = oneshot::channel();
+        let (old_ticket, _) = queue.enter(session_id, None, matched);
+        let (matched, _new) = oneshot::channel();
+        let (_, info) = queue.enter(session_id, None, matched);
+        assert_eq!(info.waiting, 1);
+        // timeout of earlier entry does not remove the new one
+        assert!(queue.expire(&old_ticket).is_none());
+        assert!(queue.leave(&session_id).is_some());
+    }
+}
@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::sync::Mutex;
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::remote_room::RoomRequest;
+use super::RoomInfo;
+
+/// Message between server instances
+#[derive(Serialize, Deserialize, Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct BusMessage {
+    /// instance which published the message
+    pub from: String,
+    /// `None` if every instance should read it
+    pub to: Option<String>,
+    pub body: BusBody,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum BusBody {
+    /// Room hosted by sender, sent when it changes and on every sweep
+    RoomListed {
+        info: RoomInfo,
+        invite_code: String,
+        /// salt to hash password with before asking to join,
+        /// `None` if room has no password
+        password_salt: Option<String>,
+    },
+    /// Room hosted by sender is closed
+    RoomUnlisted { room_id: Uuid },
+    /// Request to room hosted by receiver
+    Request {
+        request_id: Uuid,
+        room_id: Uuid,
+        request: RoomRequest,
+    },
+    /// Result of request, `None` if room is not found
+    Reply {
+        request_id: Uuid,
+        room_id: Uuid,
+        result: Option<serde_json::Value>,
+    },
+    /// Message for session connected to receiver
+    Deliver { session_id: Uuid, message: String },
+    /// Session connected to receiver is removed from room by its host
+    MemberRemoved { room_id: Uuid, session_id: Uuid },
+}
+
+/// Carries messages between server instances, so that sessions on any
+/// instance can share rooms and lobby. Sessions stay on the instance they
+/// connected to, rooms stay on the instance they were created on
+pub trait MessageBus: Send + Sync {
+    /// Send message to every instance, sender included
+    fn publish(&self, message: &BusMessage) -> Result<(), Box<dyn Error>>;
+    /// Deliver messages published by any instance to `recipient`
+    fn subscribe(&self, recipient: Recipient<BusMessage>) -> Result<(), Box<dyn Error>>;
+}
+
+/// Bus within this process, for a single instance
+#[derive(Default)]
+pub struct LocalBus {
+    subscribers: Mutex<Vec<Recipient<BusMessage>>>,
+}
+
+impl MessageBus for LocalBus {
+    fn publish(&self, message: &BusMessage) -> Result<(), Box<dyn Error>> {
+        let mut subscribers = self.subscribers.lock().map_err(|_| "bus is poisoned")?;
+        subscribers.retain(|subscriber| subscriber.connected());
+        for subscriber in subscribers.iter() {
+            let _ = subscriber.do_send(message.clone());
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, recipient: Recipient<BusMessage>) -> Result<(), Box<dyn Error>> {
+        self.subscribers
+            .lock()
+            .map_err(|_| "bus is poisoned")?
+            .push(recipient);
+        Ok(())
+    }
+}
@@ -0,0 +1,634 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::board::HIDDEN_CARD_ID;
+use super::*;
+
+/// Newest protocol version this server speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version this server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Capabilities and the protocol version they are available since.
+/// Version is bumped once per release, capabilities added until the
+/// release go under the same version
+const CAPABILITIES: &[(&str, u32)] = &[
+    ("legacy-command", 1),
+    ("json-request", 2),
+    ("resume", 2),
+    ("replay", 2),
+    ("request-id", 2),
+    ("error-code", 2),
+    ("leave", 2),
+    ("roster", 2),
+    ("chat", 2),
+    ("host-migration", 2),
+    ("room-settings", 2),
+    ("private-room", 2),
+    ("spectator", 2),
+    ("moderation", 2),
+    ("room-state", 2),
+    ("lobby", 2),
+    ("room-expiry", 2),
+    ("matchmaking", 2),
+    ("board", 2),
+    ("deck", 2),
+    ("fair-shuffle", 2),
+];
+
+/// Id client attaches to request, echoed back on its response as is
+pub type RequestId = serde_json::Value;
+
+/// Request can not be parsed, answered as error for the event
+pub type RequestError = (Event, ServerError);
+
+/// Parse json request, `request_id` is picked even if the rest is malformed
+pub fn parse_request(text: &str) -> (Option<RequestId>, Result<WsRequest, RequestError>) {
+    let mut value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(error) => return (None, Err(invalid_request(error))),
+    };
+    let request_id = value
+        .as_object_mut()
+        .and_then(|object| object.remove("request_id"));
+    (
+        request_id,
+        serde_json::from_value(value).map_err(invalid_request),
+    )
+}
+
+fn invalid_request(error: serde_json::Error) -> RequestError {
+    let message = error.to_string();
+    // serde reports unexpected "cmd" as unknown variant
+    let code = if message.starts_with("unknown variant") {
+        ErrorCode::UnknownCommand
+    } else {
+        ErrorCode::MalformedPayload
+    };
+    (
+        Event::Unknown,
+        ServerError::new(code, &format!("invalid request: {}", message)),
+    )
+}
+
+fn malformed(event: Event, message: &str) -> RequestError {
+    (
+        event,
+        ServerError::new(ErrorCode::MalformedPayload, message),
+    )
+}
+
+/// Pick protocol version for client, `None` if client is too old
+pub fn negotiate_version(requested: u32) -> Option<u32> {
+    if requested < MIN_PROTOCOL_VERSION {
+        None
+    } else {
+        Some(requested.min(PROTOCOL_VERSION))
+    }
+}
+
+/// Whether `capability` is available in `version`
+pub fn supports(version: u32, capability: &str) -> bool {
+    CAPABILITIES
+        .iter()
+        .any(|(name, since)| *name == capability && *since <= version)
+}
+
+/// Capability event came with, `None` for events of the first version
+fn capability_of(event: &Event) -> Option<&'static str> {
+    match event {
+        Event::Hello
+        | Event::CreateRoom
+        | Event::EnterRoom
+        | Event::GetRoomList
+        | Event::SomeoneEnterRoom
+        | Event::FirstCardsInfo
+        | Event::CardsInfo
+        | Event::Unknown => None,
+        Event::Session | Event::Resume => Some("resume"),
+        Event::Replay => Some("replay"),
+        Event::LeaveRoom | Event::SomeoneLeaveRoom => Some("leave"),
+        Event::SetName | Event::Roster => Some("roster"),
+        Event::Chat | Event::ChatHistory => Some("chat"),
+        Event::HostChanged => Some("host-migration"),
+        Event::Kicked | Event::LockRoom => Some("moderation"),
+        Event::Ready | Event::RoomState | Event::StartGame | Event::FinishGame => {
+            Some("room-state")
+        }
+        Event::Subscribe
+        | Event::Unsubscribe
+        | Event::RoomAdded
+        | Event::RoomUpdated
+        | Event::RoomRemoved => Some("lobby"),
+        Event::RoomClosed => Some("room-expiry"),
+        Event::Matchmake | Event::CancelMatchmaking | Event::MatchFound => Some("matchmaking"),
+        Event::BoardSnapshot | Event::MoveCards => Some("board"),
+        Event::PlayDeck => Some("deck"),
+        Event::SeedCommitted | Event::SeedRevealed => Some("fair-shuffle"),
+    }
+}
+
+/// Message pushed by room or chat server as client speaking `version`
+/// understands it, `None` if its event is newer than the version.
+/// Events whose payload changed since are sent with the old payload
+pub fn for_version(version: u32, message: String) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Pushed {
+        event: Event,
+    }
+    let event = match serde_json::from_str::<Pushed>(&message) {
+        Ok(pushed) => pushed.event,
+        Err(_) => return Some(message),
+    };
+    match (capability_of(&event), event) {
+        (Some(capability), _) if !supports(version, capability) => None,
+        // members entering were not told apart before roster
+        (_, Event::SomeoneEnterRoom) if !supports(version, "roster") => {
+            let mut value: serde_json::Value = serde_json::from_str(&message).ok()?;
+            value["data"] = serde_json::json!(SimpleMessage {
+                message: "Someone is connected".to_string(),
+            });
+            Some(value.to_string())
+        }
+        _ => Some(message),
+    }
+}
+
+pub fn protocol_info(version: u32) -> ProtocolInfo {
+    ProtocolInfo {
+        version,
+        supported_versions: (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).collect(),
+        capabilities: CAPABILITIES
+            .iter()
+            .filter(|(_, since)| *since <= version)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+    }
+}
+
+pub fn unsupported_version(requested: u32) -> WsResponse {
+    WsResponse::error(
+        Event::Hello,
+        ServerError::new(
+            ErrorCode::UnsupportedVersion,
+            &format!(
+                "unsupported protocol version: {}, supported versions: {}..={}",
+                requested, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        ),
+    )
+}
+
+/// Client request for websocket
+///
+/// Every frame is a json object such as `{"cmd": "Join", "data": {"room_id": "..."}}`,
+/// optionally with `"request_id"` to match the response
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "cmd", content = "data")]
+pub enum WsRequest {
+    /// Declare protocol version client speaks
+    Hello {
+        version: u32,
+        /// id client keeps across connections, bans are matched on it
+        #[serde(default)]
+        client_id: Option<String>,
+    },
+    /// Take over disconnected session
+    Resume { resume_token: Uuid },
+    /// List rooms
+    List,
+    /// Get page of rooms and their changes after
+    Subscribe {
+        #[serde(default)]
+        query: RoomQuery,
+    },
+    /// Stop getting room changes
+    Unsubscribe,
+    /// Join room, leaving current one
+    Join {
+        room_id: Uuid,
+        #[serde(default)]
+        password: Option<String>,
+        /// watch without playing
+        #[serde(default)]
+        spectate: bool,
+    },
+    /// Join room by invite code, leaving current one
+    JoinByCode {
+        invite_code: String,
+        #[serde(default)]
+        password: Option<String>,
+        /// watch without playing
+        #[serde(default)]
+        spectate: bool,
+    },
+    /// Wait for another player and join a room with them
+    Matchmake {
+        /// game or format to play
+        #[serde(default)]
+        tag: Option<String>,
+    },
+    /// Stop waiting for another player
+    CancelMatchmaking,
+    /// Leave current room
+    Leave,
+    /// Set display name
+    SetName { name: String },
+    /// Send chat message to room members
+    Chat { text: String },
+    /// Create room
+    Create {
+        name: String,
+        #[serde(default)]
+        settings: RoomSettings,
+        /// password required to join
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Remove member from room, host only
+    Kick { session_id: Uuid },
+    /// Remove member from room and keep it out, host only
+    Ban { session_id: Uuid },
+    /// Refuse new members or accept them again, host only
+    Lock { locked: bool },
+    /// Get ready for next game or cancel it
+    Ready { ready: bool },
+    /// End game being played, host only
+    Finish,
+    /// Send first cards info to room members
+    FirstCards { cards: Vec<CardInfo> },
+    /// Send cards info (not first) to room members
+    Cards { cards: Vec<CardInfo> },
+    /// Move cards on room board
+    MoveCards { moves: Vec<CardMove> },
+    /// Put cards of stored deck on room board
+    PlayDeck { deck_id: i32 },
+    /// Get room messages after seq `after` again
+    Replay { after: u64 },
+}
+
+impl WsRequest {
+    /// Parse old "/command args" style message.
+    /// TODO: remove after all clients send json requests
+    pub fn from_legacy(text: &str) -> Result<WsRequest, RequestError> {
+        let v: Vec<&str> = text.splitn(2, ' ').collect();
+        match (v[0], v.get(1)) {
+            ("/list", _) => Ok(WsRequest::List),
+            ("/leave", _) => Ok(WsRequest::Leave),
+            ("/name", Some(name)) => Ok(WsRequest::SetName {
+                name: name.to_string(),
+            }),
+            ("/name", None) => Err(malformed(Event::SetName, "name is required")),
+            ("/chat", Some(text)) => Ok(WsRequest::Chat {
+                text: text.to_string(),
+            }),
+            ("/chat", None) => Err(malformed(Event::Chat, "text is required")),
+            ("/join", Some(room_id)) => match Uuid::parse_str(room_id) {
+                Ok(room_id) => Ok(WsRequest::Join {
+                    room_id,
+                    password: None,
+                    spectate: false,
+                }),
+                Err(_) => Err(malformed(Event::EnterRoom, "invalid room id")),
+            },
+            ("/join", None) => Err(malformed(Event::EnterRoom, "room id is required")),
+            ("/create", Some(name)) => Ok(WsRequest::Create {
+                name: name.to_string(),
+                settings: RoomSettings::default(),
+                password: None,
+            }),
+            ("/create", None) => Err(malformed(Event::CreateRoom, "room name is required")),
+            ("/first-cards", Some(cards)) => match serde_json::from_str(cards) {
+                Ok(cards) => Ok(WsRequest::FirstCards { cards }),
+                Err(_) => Err(malformed(Event::FirstCardsInfo, "invalid cards info")),
+            },
+            ("/cards", Some(cards)) => match serde_json::from_str(cards) {
+                Ok(cards) => Ok(WsRequest::Cards { cards }),
+                Err(_) => Err(malformed(Event::CardsInfo, "invalid cards info")),
+            },
+            ("/first-cards", None) => {
+                Err(malformed(Event::FirstCardsInfo, "cards info is required"))
+            }
+            ("/cards", None) => Err(malformed(Event::CardsInfo, "cards info is required")),
+            _ => Err((
+                Event::Unknown,
+                ServerError::new(
+                    ErrorCode::UnknownCommand,
+                    &format!("unknown command: {:?}", text),
+                ),
+            )),
+        }
+    }
+}
+
+/// Server response for websocket
+///
+/// Serialized as `WsMessage` so that `event` tells clients the type of `data`
+#[derive(Debug, Clone)]
+pub enum WsResponse {
+    /// negotiated protocol version
+    Hello(ProtocolInfo),
+    /// session id and resume token
+    Session(SessionInfo),
+    /// disconnected session is resumed
+    Resumed(SessionInfo),
+    /// room is created
+    CreateRoom(RoomInfo),
+    /// entered room
+    EnterRoom(RoomInfo),
+    /// room list
+    GetRoomList(RoomInfoList),
+    /// someone entered the room
+    SomeoneEnterRoom(RosterEntry),
+    /// left room
+    LeaveRoom(RoomInfo),
+    /// someone left the room
+    SomeoneLeaveRoom(RoomInfo),
+    /// display name is set
+    SetName(PlayerName),
+    /// room members changed
+    Roster(RoomRoster),
+    /// host left and another member became host
+    HostChanged(RosterEntry),
+    /// first cards info from room member
+    FirstCardsInfo(CardInfoList),
+    /// cards info (not first) from room member
+    CardsInfo(CardInfoList),
+    /// chat message in room
+    Chat(ChatEntry),
+    /// recent chat messages of entered room
+    ChatHistory(Vec<ChatEntry>),
+    /// missed room messages are sent again
+    Replayed(ReplayInfo),
+    /// member is removed by host, sent to host and the member
+    Kicked(KickInfo),
+    /// room is locked or unlocked by host
+    LockRoom(RoomInfo),
+    /// ready toggle of player
+    Ready(RosterEntry),
+    /// room went back to lobby or into ready check
+    RoomState(RoomInfo),
+    /// every player is ready and game started
+    StartGame(RoomInfo),
+    /// game is over
+    FinishGame(RoomInfo),
+    /// subscribed to lobby, with first page of rooms
+    Subscribed(RoomInfoList),
+    /// unsubscribed from lobby
+    Unsubscribed,
+    /// room added to lobby
+    RoomAdded(RoomInfo),
+    /// room in lobby changed
+    RoomUpdated(RoomInfo),
+    /// room gone from lobby
+    RoomRemoved(RoomInfo),
+    /// room is closed by server
+    RoomClosed(RoomClosed),
+    /// waiting for another player
+    Queued(QueueInfo),
+    /// stopped waiting for another player
+    MatchmakingCancelled(QueueInfo),
+    /// another player is found and both joined the room
+    MatchFound(RoomInfo),
+    /// every card on board, sent on entering or resuming room
+    BoardSnapshot(BoardState),
+    /// cards moved on board
+    CardsMoved(BoardState),
+    /// cards of deck put on board
+    DeckDealt(BoardState),
+    /// hash of seed for shuffles of game being played
+    SeedCommitted(GameSeed),
+    /// seed of finished game, to check shuffles with
+    SeedRevealed(GameSeed),
+    /// request for `Event` failed
+    Error(Event, ServerError),
+}
+
+#[derive(Serialize, Debug)]
+struct WsMessage<'a, T> {
+    data: &'a T,
+    event: Event,
+    status: Status,
+    /// seq of room broadcast
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    /// id of the request this message answers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a RequestId>,
+}
+
+impl WsResponse {
+    pub fn error(event: Event, error: ServerError) -> WsResponse {
+        WsResponse::Error(event, error)
+    }
+
+    pub fn event(&self) -> Event {
+        match self {
+            WsResponse::Hello(_) => Event::Hello,
+            WsResponse::Session(_) => Event::Session,
+            WsResponse::Resumed(_) => Event::Resume,
+            WsResponse::CreateRoom(_) => Event::CreateRoom,
+            WsResponse::EnterRoom(_) => Event::EnterRoom,
+            WsResponse::GetRoomList(_) => Event::GetRoomList,
+            WsResponse::SomeoneEnterRoom(_) => Event::SomeoneEnterRoom,
+            WsResponse::LeaveRoom(_) => Event::LeaveRoom,
+            WsResponse::SomeoneLeaveRoom(_) => Event::SomeoneLeaveRoom,
+            WsResponse::SetName(_) => Event::SetName,
+            WsResponse::Roster(_) => Event::Roster,
+            WsResponse::HostChanged(_) => Event::HostChanged,
+            WsResponse::FirstCardsInfo(_) => Event::FirstCardsInfo,
+            WsResponse::CardsInfo(_) => Event::CardsInfo,
+            WsResponse::Chat(_) => Event::Chat,
+            WsResponse::ChatHistory(_) => Event::ChatHistory,
+            WsResponse::Replayed(_) => Event::Replay,
+            WsResponse::Kicked(_) => Event::Kicked,
+            WsResponse::LockRoom(_) => Event::LockRoom,
+            WsResponse::Ready(_) => Event::Ready,
+            WsResponse::RoomState(_) => Event::RoomState,
+            WsResponse::StartGame(_) => Event::StartGame,
+            WsResponse::FinishGame(_) => Event::FinishGame,
+            WsResponse::Subscribed(_) => Event::Subscribe,
+            WsResponse::Unsubscribed => Event::Unsubscribe,
+            WsResponse::RoomAdded(_) => Event::RoomAdded,
+            WsResponse::RoomUpdated(_) => Event::RoomUpdated,
+            WsResponse::RoomRemoved(_) => Event::RoomRemoved,
+            WsResponse::RoomClosed(_) => Event::RoomClosed,
+            WsResponse::Queued(_) => Event::Matchmake,
+            WsResponse::MatchmakingCancelled(_) => Event::CancelMatchmaking,
+            WsResponse::MatchFound(_) => Event::MatchFound,
+            WsResponse::BoardSnapshot(_) => Event::BoardSnapshot,
+            WsResponse::CardsMoved(_) => Event::MoveCards,
+            WsResponse::DeckDealt(_) => Event::PlayDeck,
+            WsResponse::SeedCommitted(_) => Event::SeedCommitted,
+            WsResponse::SeedRevealed(_) => Event::SeedRevealed,
+            WsResponse::Error(event, _) => event.clone(),
+        }
+    }
+
+    /// Same response as `viewer` sees it, with backs in place of faces hidden
+    /// from the viewer and their card ids hidden too. `None` if nothing is hidden.
+    /// Old style cards are sent only by their owner, so other members never
+    /// see faces of private ones
+    pub fn for_viewer(&self, viewer: Option<&Uuid>) -> Option<WsResponse> {
+        let public = |list: &CardInfoList| {
+            if list.cards.iter().any(|card| card.private) {
+                Some(CardInfoList {
+                    cards: list
+                        .cards
+                        .iter()
+                        .map(|card| {
+                            if card.private {
+                                CardInfo {
+                                    id: HIDDEN_CARD_ID,
+                                    face: card.back.clone(),
+                                    ..card.clone()
+                                }
+                            } else {
+                                card.clone()
+                            }
+                        })
+                        .collect(),
+                })
+            } else {
+                None
+            }
+        };
+        match self {
+            WsResponse::FirstCardsInfo(list) => public(list).map(WsResponse::FirstCardsInfo),
+            WsResponse::CardsInfo(list) => public(list).map(WsResponse::CardsInfo),
+            WsResponse::BoardSnapshot(state) => {
+                state.for_viewer(viewer).map(WsResponse::BoardSnapshot)
+            }
+            WsResponse::CardsMoved(state) => state.for_viewer(viewer).map(WsResponse::CardsMoved),
+            WsResponse::DeckDealt(state) => state.for_viewer(viewer).map(WsResponse::DeckDealt),
+            _ => None,
+        }
+    }
+
+    /// Owners of private cards in response, who see it unlike other members
+    pub fn private_owners(&self) -> HashSet<Uuid> {
+        match self {
+            WsResponse::BoardSnapshot(state)
+            | WsResponse::CardsMoved(state)
+            | WsResponse::DeckDealt(state) => state.private_owners(),
+            _ => HashSet::new(),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            WsResponse::Error(_, _) => Status::Error,
+            _ => Status::Ok,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        self.serialize(None, None)
+    }
+
+    /// Serialize as room broadcast numbered `seq`
+    pub fn to_json_with_seq(&self, seq: u64) -> String {
+        self.serialize(Some(seq), None)
+    }
+
+    /// Serialize as response to request `request_id`
+    pub fn to_json_for(&self, request_id: Option<&RequestId>) -> String {
+        self.serialize(None, request_id)
+    }
+
+    fn serialize(&self, seq: Option<u64>, request_id: Option<&RequestId>) -> String {
+        match self {
+            WsResponse::Hello(info) => self.envelope(seq, request_id, info),
+            WsResponse::Session(info) | WsResponse::Resumed(info) => {
+                self.envelope(seq, request_id, info)
+            }
+            WsResponse::CreateRoom(room)
+            | WsResponse::EnterRoom(room)
+            | WsResponse::LeaveRoom(room)
+            | WsResponse::SomeoneLeaveRoom(room)
+            | WsResponse::LockRoom(room)
+            | WsResponse::RoomState(room)
+            | WsResponse::StartGame(room)
+            | WsResponse::FinishGame(room)
+            | WsResponse::RoomAdded(room)
+            | WsResponse::RoomUpdated(room)
+            | WsResponse::RoomRemoved(room)
+            | WsResponse::MatchFound(room) => self.envelope(seq, request_id, room),
+            WsResponse::GetRoomList(list) => self.envelope(seq, request_id, &list.rooms),
+            WsResponse::Subscribed(list) => self.envelope(seq, request_id, list),
+            WsResponse::Unsubscribed => self.envelope(seq, request_id, &()),
+            WsResponse::Queued(info) | WsResponse::MatchmakingCancelled(info) => {
+                self.envelope(seq, request_id, info)
+            }
+            WsResponse::FirstCardsInfo(list) | WsResponse::CardsInfo(list) => {
+                self.envelope(seq, request_id, &list.cards)
+            }
+            WsResponse::BoardSnapshot(state)
+            | WsResponse::CardsMoved(state)
+            | WsResponse::DeckDealt(state) => self.envelope(seq, request_id, state),
+            WsResponse::SeedCommitted(seed) | WsResponse::SeedRevealed(seed) => {
+                self.envelope(seq, request_id, seed)
+            }
+            WsResponse::Replayed(info) => self.envelope(seq, request_id, info),
+            WsResponse::Kicked(info) => self.envelope(seq, request_id, info),
+            WsResponse::RoomClosed(closed) => self.envelope(seq, request_id, closed),
+            WsResponse::SomeoneEnterRoom(entry)
+            | WsResponse::HostChanged(entry)
+            | WsResponse::Ready(entry) => self.envelope(seq, request_id, entry),
+            WsResponse::SetName(name) => self.envelope(seq, request_id, name),
+            WsResponse::Roster(roster) => self.envelope(seq, request_id, roster),
+            WsResponse::Chat(entry) => self.envelope(seq, request_id, entry),
+            WsResponse::ChatHistory(entries) => self.envelope(seq, request_id, entries),
+            WsResponse::Error(_, error) => self.envelope(seq, request_id, error),
+        }
+    }
+
+    fn envelope<T: Serialize>(
+        &self,
+        seq: Option<u64>,
+        request_id: Option<&RequestId>,
+        data: &T,
+    ) -> String {
+        serde_json::to_string(&WsMessage {
+            data,
+            event: self.event(),
+            status: self.status(),
+            seq,
+            request_id,
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushed_events_are_sent_as_version_knows_them() {
+        let entry = RosterEntry {
+            session_id: Uuid::new_v4(),
+            name: "Alice".to_string(),
+            seat: Some(1),
+            spectator: false,
+            host: false,
+            ready: false,
+        };
+        let entered = WsResponse::SomeoneEnterRoom(entry.clone()).to_json_with_seq(3);
+        let old: serde_json::Value =
+            serde_json::from_str(&for_version(1, entered.clone()).unwrap()).unwrap();
+        assert_eq!(old["data"]["message"], "Someone is connected");
+        assert_eq!(old["event"], "SomeoneEnterRoom");
+        assert_eq!(for_version(2, entered.clone()), Some(entered));
+
+        let roster = WsResponse::Roster(RoomRoster {
+            room_id: Uuid::new_v4(),
+            members: vec![entry],
+        })
+        .to_json();
+        assert_eq!(for_version(1, roster.clone()), None);
+        assert_eq!(for_version(2, roster.clone()), Some(roster));
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::channel::oneshot;
+use futures::FutureExt;
+use serde::de::DeserializeOwned;
+
+use super::message_bus::{BusBody, BusMessage, MessageBus};
+use super::room::JoinPassword;
+use super::room_actor::RoomActor;
+use super::Message;
+use super::*;
+
+/// How long request to room on another instance waits for reply
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Room request sent over message bus
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum RoomRequest {
+    AddMember {
+        session_id: Uuid,
+        name: Option<String>,
+        client_id: Option<String>,
+        /// whether session is connected, messages are delivered over bus
+        connected: bool,
+        resume_token: Uuid,
+        /// password hashed with salt of the room, never the password itself
+        password_hash: Option<String>,
+        spectate: bool,
+    },
+    RemoveMember(RemoveMember),
+    RenameMember(RenameMember),
+    MemberAddress {
+        session_id: Uuid,
+        connected: bool,
+        resume_token: Uuid,
+    },
+    Message(Message),
+    MoveCards(MoveCards),
+    PlayDeck(PlayDeck),
+    Replay(Replay),
+    SendChat(SendChat),
+    Kick(Kick),
+    SetReady(SetReady),
+    FinishGame(FinishGame),
+    Lock(Lock),
+}
+
+/// Message room actor handles which can be sent to room on another instance
+pub trait RemoteMessage: actix::Message + Send + 'static {
+    fn into_request(self) -> RoomRequest;
+}
+
+impl RemoteMessage for AddMember {
+    fn into_request(self) -> RoomRequest {
+        RoomRequest::AddMember {
+            session_id: self.session_id,
+            name: self.name,
+            client_id: self.client_id,
+            connected: self.address.is_some(),
+            resume_token: self.resume_token,
+            // password in plain text is only for rooms on the same instance
+            password_hash: match self.password {
+                Some(JoinPassword::Hashed(hash)) => Some(hash),
+                _ => None,
+            },
+            spectate: self.spectate,
+        }
+    }
+}
+
+impl RemoteMessage for MemberAddress {
+    fn into_request(self) -> RoomRequest {
+        RoomRequest::MemberAddress {
+            session_id: self.session_id,
+            connected: self.address.is_some(),
+            resume_token: self.resume_token,
+        }
+    }
+}
+
+macro_rules! remote_message {
+    ($($message:ident),*) => {
+        $(
+            impl RemoteMessage for $message {
+                fn into_request(self) -> RoomRequest {
+                    RoomRequest::$message(self)
+                }
+            }
+        )*
+    };
+}
+
+remote_message!(
+    RemoveMember,
+    RenameMember,
+    Message,
+    MoveCards,
+    PlayDeck,
+    Replay,
+    SendChat,
+    Kick,
+    SetReady,
+    FinishGame,
+    Lock
+);
+
+/// Room a session is in, either on this instance or on another one
+#[derive(Clone)]
+pub enum RoomLink {
+    Local(Addr<RoomActor>),
+    Remote(Addr<RemoteRoom>),
+}
+
+impl RoomLink {
+    /// Send message to room and wait for its result
+    pub fn send<M>(&self, msg: M) -> impl Future<Output = Result<M::Result, MailboxError>>
+    where
+        M: RemoteMessage,
+        M::Result: Serialize + DeserializeOwned + Send,
+        RoomActor: Handler<M>,
+    {
+        match self {
+            RoomLink::Local(addr) => addr.send(msg).left_future(),
+            RoomLink::Remote(addr) => addr
+                .send(Forward(msg))
+                .map(|res| res.and_then(|result| result))
+                .right_future(),
+        }
+    }
+
+    /// Send message to room without waiting for its result
+    pub fn do_send<M>(&self, msg: M)
+    where
+        M: RemoteMessage,
+        M::Result: Serialize + DeserializeOwned + Send,
+        RoomActor: Handler<M>,
+    {
+        match self {
+            RoomLink::Local(addr) => addr.do_send(msg),
+            RoomLink::Remote(addr) => addr.do_send(Forward(msg)),
+        }
+    }
+}
+
+/// Request to room on another instance
+pub struct Forward<M>(M);
+
+impl<M> actix::Message for Forward<M>
+where
+    M: RemoteMessage,
+    M::Result: Send,
+{
+    /// `MailboxError::Timeout` if the instance does not reply in time
+    type Result = Result<M::Result, MailboxError>;
+}
+
+/// Reply from instance hosting the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReplyArrived {
+    pub request_id: Uuid,
+    pub result: Option<serde_json::Value>,
+}
+
+/// `RemoteRoom` stands for room on another instance. It sends requests
+/// over message bus and waits for replies the chat server passes to it
+pub struct RemoteRoom {
+    room_id: Uuid,
+    /// this instance
+    instance: String,
+    /// instance hosting the room
+    host: String,
+    bus: Arc<dyn MessageBus>,
+    /// requests waiting for reply
+    pending: HashMap<Uuid, oneshot::Sender<Option<serde_json::Value>>>,
+}
+
+impl RemoteRoom {
+    pub fn new(
+        room_id: Uuid,
+        instance: String,
+        host: String,
+        bus: Arc<dyn MessageBus>,
+    ) -> RemoteRoom {
+        RemoteRoom {
+            room_id,
+            instance,
+            host,
+            bus,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for RemoteRoom {
+    type Context = Context<Self>;
+}
+
+impl<M> Handler<Forward<M>> for RemoteRoom
+where
+    M: RemoteMessage,
+    M::Result: DeserializeOwned + Send,
+{
+    type Result = ResponseFuture<Result<M::Result, MailboxError>>;
+
+    fn handle(&mut self, msg: Forward<M>, ctx: &mut Context<Self>) -> Self::Result {
+        let request_id = Uuid::new_v4();
+        let published = self.bus.publish(&BusMessage {
+            from: self.instance.clone(),
+            to: Some(self.host.clone()),
+            body: BusBody::Request {
+                request_id,
+                room_id: self.room_id,
+                request: msg.0.into_request(),
+            },
+        });
+        if let Err(error) = published {
+            println!("Failed to send request to room {}: {}", self.room_id, error);
+            return Box::pin(async { Err(MailboxError::Closed) });
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(request_id, sender);
+        // dropping sender makes receiver fail
+        ctx.run_later(REQUEST_TIMEOUT, move |act, _| {
+            act.pending.remove(&request_id);
+        });
+        Box::pin(async move {
+            match receiver.await {
+                Ok(Some(result)) => {
+                    serde_json::from_value(result).map_err(|_| MailboxError::Closed)
+                }
+                Ok(None) => Err(MailboxError::Closed),
+                Err(_) => Err(MailboxError::Timeout),
+            }
+        })
+    }
+}
+
+/// Handler for ReplyArrived message.
+impl Handler<ReplyArrived> for RemoteRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReplyArrived, _: &mut Context<Self>) {
+        if let Some(sender) = self.pending.remove(&msg.request_id) {
+            let _ = sender.send(msg.result);
+        }
+    }
+}
+
+/// `RemoteSession` stands for member connected to another instance,
+/// room messages to it are delivered over message bus
+pub struct RemoteSession {
+    session_id: Uuid,
+    /// this instance
+    instance: String,
+    /// instance the session is connected to
+    host: String,
+    bus: Arc<dyn MessageBus>,
+}
+
+impl RemoteSession {
+    pub fn new(
+        session_id: Uuid,
+        instance: String,
+        host: String,
+        bus: Arc<dyn MessageBus>,
+    ) -> RemoteSession {
+        RemoteSession {
+            session_id,
+            instance,
+            host,
+            bus,
+        }
+    }
+}
+
+impl Actor for RemoteSession {
+    type Context = Context<Self>;
+}
+
+/// Handler for ChatMessage message.
+impl Handler<ChatMessage> for RemoteSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChatMessage, _: &mut Context<Self>) {
+        let published = self.bus.publish(&BusMessage {
+            from: self.instance.clone(),
+            to: Some(self.host.clone()),
+            body: BusBody::Deliver {
+                session_id: self.session_id,
+                message: msg.0,
+            },
+        });
+        if let Err(error) = published {
+            println!("Failed to deliver to {}: {}", self.session_id, error);
+        }
+    }
+}
@@ -0,0 +1,863 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::board::Board;
+use super::room_store::SavedRoom;
+use super::shuffle::{to_hex, Seed};
+use super::*;
+
+/// How many broadcasts each room keeps for replay
+const ROOM_HISTORY_SIZE: usize = 256;
+/// Longest display name in chars
+const NAME_MAX_LEN: usize = 24;
+/// Display name of members who have not set one
+const DEFAULT_NAME: &str = "Player";
+/// How many chat messages each room keeps for members joining later
+const CHAT_HISTORY_SIZE: usize = 50;
+/// Longest chat message in chars
+const CHAT_MAX_LEN: usize = 500;
+/// Most players a room can be created for
+const MAX_PLAYERS_LIMIT: usize = 8;
+/// Longest game title in chars
+const GAME_TITLE_MAX_LEN: usize = 64;
+/// Fewest players to start game, unless room is for fewer
+const MIN_PLAYERS_TO_START: usize = 2;
+/// Longest client id in chars
+const CLIENT_ID_MAX_LEN: usize = 64;
+/// Longest room password in chars
+const PASSWORD_MAX_LEN: usize = 64;
+/// Length of invite code
+const INVITE_CODE_LEN: usize = 6;
+/// Characters of invite code, without ones easy to confuse (0/O, 1/I/L)
+const INVITE_CODE_CHARS: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+/// Random code players can type to join room
+pub fn generate_invite_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..INVITE_CODE_LEN)
+        .map(|_| INVITE_CODE_CHARS[rng.gen_range(0, INVITE_CODE_CHARS.len())] as char)
+        .collect()
+}
+
+/// Check display name and trim surrounding spaces
+pub fn validate_name(name: &str) -> Result<String, ServerError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > NAME_MAX_LEN {
+        return Err(ServerError::new(
+            ErrorCode::InvalidName,
+            &format!("name must be 1 to {} characters", NAME_MAX_LEN),
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(ServerError::new(
+            ErrorCode::InvalidName,
+            "name must not contain control characters",
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Check id client keeps across connections
+pub fn validate_client_id(client_id: &str) -> Result<(), ServerError> {
+    if client_id.is_empty() || client_id.chars().count() > CLIENT_ID_MAX_LEN {
+        return Err(ServerError::new(
+            ErrorCode::MalformedPayload,
+            &format!("client id must be 1 to {} characters", CLIENT_ID_MAX_LEN),
+        ));
+    }
+    Ok(())
+}
+
+/// Check chat message and trim surrounding spaces
+pub fn validate_chat(text: &str) -> Result<String, ServerError> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > CHAT_MAX_LEN {
+        return Err(ServerError::new(
+            ErrorCode::InvalidChat,
+            &format!("chat message must be 1 to {} characters", CHAT_MAX_LEN),
+        ));
+    }
+    Ok(text.to_string())
+}
+
+/// Check settings requested on room creation
+pub fn validate_settings(settings: &RoomSettings) -> Result<(), ServerError> {
+    if settings.max_players == 0 || settings.max_players > MAX_PLAYERS_LIMIT {
+        return Err(ServerError::new(
+            ErrorCode::InvalidSettings,
+            &format!("max players must be 1 to {}", MAX_PLAYERS_LIMIT),
+        ));
+    }
+    if let Some(title) = &settings.game_title {
+        if title.chars().count() > GAME_TITLE_MAX_LEN {
+            return Err(ServerError::new(
+                ErrorCode::InvalidSettings,
+                &format!(
+                    "game title must be at most {} characters",
+                    GAME_TITLE_MAX_LEN
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check password requested on room creation
+pub fn validate_password(password: &str) -> Result<(), ServerError> {
+    if password.is_empty() || password.chars().count() > PASSWORD_MAX_LEN {
+        return Err(ServerError::new(
+            ErrorCode::InvalidSettings,
+            &format!("password must be 1 to {} characters", PASSWORD_MAX_LEN),
+        ));
+    }
+    Ok(())
+}
+
+/// Password session joins room with
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinPassword {
+    /// as client sent it, for room on this instance
+    Plain(String),
+    /// hashed with salt of the room, for room on another instance,
+    /// so that password itself is never sent over message bus
+    Hashed(String),
+}
+
+/// Salted SHA-256 of room password, the password itself is not kept
+#[derive(Serialize, Deserialize)]
+struct PasswordHash {
+    salt: String,
+    hash: String,
+}
+
+impl PasswordHash {
+    fn new(password: &str) -> PasswordHash {
+        let salt = to_hex(&rand::thread_rng().gen::<[u8; 16]>());
+        PasswordHash {
+            hash: hash_password(&salt, password),
+            salt,
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        hash_password(&self.salt, password) == self.hash
+    }
+}
+
+pub fn hash_password(salt: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password);
+    to_hex(&hasher.finalize())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Member {
+    session_id: Uuid,
+    /// display name, unique in the room
+    name: String,
+    /// name player asked for, `None` if it has not set one
+    asked_name: Option<String>,
+    /// id client keeps across connections
+    client_id: Option<String>,
+    /// `None` for spectators
+    seat: Option<usize>,
+    /// ready for next game
+    ready: bool,
+}
+
+impl Member {
+    fn is_spectator(&self) -> bool {
+        self.seat.is_none()
+    }
+}
+
+/// Client kept out of room. New connections get new session ids, so that
+/// client id and name of the banned member are matched too
+#[derive(Serialize, Deserialize)]
+struct Ban {
+    session_id: Uuid,
+    client_id: Option<String>,
+    /// name member asked for, default name is not banned
+    name: Option<String>,
+}
+
+impl Ban {
+    fn matches(&self, session_id: &Uuid, client_id: Option<&str>, name: Option<&str>) -> bool {
+        let same = |banned: &Option<String>, other: Option<&str>| match (banned, other) {
+            (Some(banned), Some(other)) => banned.to_lowercase() == other.to_lowercase(),
+            _ => false,
+        };
+        self.session_id == *session_id || same(&self.client_id, client_id) || same(&self.name, name)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Room {
+    id: Uuid,
+    name: String,
+    /// session which manages the room
+    host: Uuid,
+    settings: RoomSettings,
+    /// code players can type to join
+    invite_code: String,
+    /// password required to join
+    password: Option<PasswordHash>,
+    /// clients kicked out for good
+    banned: Vec<Ban>,
+    /// whether new members are refused
+    locked: bool,
+    state: RoomState,
+    /// last time member joined, left or sent something
+    #[serde(skip, default = "Instant::now")]
+    last_activity: Instant,
+    /// members in join order
+    members: Vec<Member>,
+    /// seq of the last broadcast
+    last_seq: u64,
+    /// recent broadcasts kept for replay
+    history: VecDeque<Broadcast>,
+    /// recent chat messages
+    chat_history: VecDeque<ChatEntry>,
+    /// cards on the table
+    board: Board,
+    /// seed of the last game started
+    seed: Option<Seed>,
+}
+
+/// Numbered message sent to room members
+#[derive(Serialize, Deserialize)]
+struct Broadcast {
+    seq: u64,
+    /// `None` if sent to every member
+    sender: Option<Uuid>,
+    message: String,
+    /// message for members who see cards of it unlike others, e.g. owners
+    /// of private cards. Everyone else, including members joining later, gets `message`
+    views: HashMap<Uuid, String>,
+}
+
+/// What is saved of room, resume tokens are kept by room actor
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<R> {
+    room: R,
+    resume_tokens: HashMap<Uuid, Uuid>,
+}
+
+impl Broadcast {
+    fn message_for(&self, member: &Member) -> &str {
+        self.views.get(&member.session_id).unwrap_or(&self.message)
+    }
+}
+
+impl Room {
+    pub fn new(
+        id: Uuid,
+        host: Uuid,
+        name: &str,
+        settings: RoomSettings,
+        invite_code: String,
+        password: Option<String>,
+    ) -> Room {
+        Room {
+            id,
+            name: name.to_owned(),
+            host,
+            settings,
+            invite_code,
+            password: password.as_deref().map(PasswordHash::new),
+            banned: Vec::new(),
+            locked: false,
+            state: RoomState::Lobby,
+            last_activity: Instant::now(),
+            members: Vec::new(),
+            last_seq: 0,
+            history: VecDeque::with_capacity(ROOM_HISTORY_SIZE),
+            chat_history: VecDeque::with_capacity(CHAT_HISTORY_SIZE),
+            board: Board::default(),
+            seed: None,
+        }
+    }
+
+    /// Room restored from saved one with resume tokens of its members
+    pub fn restore(saved: &SavedRoom) -> serde_json::Result<(Room, HashMap<Uuid, Uuid>)> {
+        let checkpoint: Checkpoint<Room> = serde_json::from_str(&saved.checkpoint)?;
+        Ok((checkpoint.room, checkpoint.resume_tokens))
+    }
+
+    /// Room to save with resume tokens of its members
+    pub fn checkpoint(
+        &self,
+        instance: &str,
+        resume_tokens: &HashMap<Uuid, Uuid>,
+    ) -> serde_json::Result<SavedRoom> {
+        let checkpoint = serde_json::to_string(&Checkpoint {
+            room: self,
+            resume_tokens: resume_tokens.clone(),
+        })?;
+        Ok(SavedRoom {
+            id: self.id,
+            instance: instance.to_string(),
+            name: self.name.to_owned(),
+            players: self.member_ids().copied().collect(),
+            checkpoint,
+        })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Room info anyone can see
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            name: self.name.to_owned(),
+            num: self.players().count(),
+            spectators: self.members.len() - self.players().count(),
+            settings: self.settings.clone(),
+            has_password: self.password.is_some(),
+            invite_code: None,
+            locked: self.locked,
+            state: self.state,
+            last_seq: self.last_seq,
+        }
+    }
+
+    /// Room info for members, with invite code
+    pub fn member_info(&self) -> RoomInfo {
+        RoomInfo {
+            invite_code: Some(self.invite_code.clone()),
+            ..self.info()
+        }
+    }
+
+    pub fn invite_code(&self) -> &str {
+        &self.invite_code
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.settings.private
+    }
+
+    /// Salt password is hashed with, `None` if room has no password
+    pub fn password_salt(&self) -> Option<&str> {
+        self.password
+            .as_ref()
+            .map(|password| password.salt.as_str())
+    }
+
+    pub fn check_password(&self, password: Option<&JoinPassword>) -> Result<(), ServerError> {
+        match (&self.password, password) {
+            (None, _) => Ok(()),
+            (Some(expected), Some(JoinPassword::Plain(password))) if expected.matches(password) => {
+                Ok(())
+            }
+            (Some(expected), Some(JoinPassword::Hashed(hash))) if expected.hash == *hash => Ok(()),
+            _ => Err(ServerError::new(
+                ErrorCode::WrongPassword,
+                "password is wrong",
+            )),
+        }
+    }
+
+    pub fn roster(&self) -> RoomRoster {
+        RoomRoster {
+            room_id: self.id,
+            members: self.members.iter().map(|m| self.entry(m)).collect(),
+        }
+    }
+
+    fn entry(&self, member: &Member) -> RosterEntry {
+        RosterEntry {
+            session_id: member.session_id,
+            name: member.name.to_owned(),
+            seat: member.seat,
+            spectator: member.is_spectator(),
+            host: member.session_id == self.host,
+            ready: member.ready,
+        }
+    }
+
+    fn players(&self) -> impl Iterator<Item = &Member> {
+        self.members.iter().filter(|m| !m.is_spectator())
+    }
+
+    pub fn member_ids(&self) -> impl Iterator<Item = &Uuid> {
+        self.members.iter().map(|member| &member.session_id)
+    }
+
+    pub fn member_name(&self, session_id: &Uuid) -> Option<String> {
+        self.members
+            .iter()
+            .find(|m| m.session_id == *session_id)
+            .map(|member| member.name.to_owned())
+    }
+
+    pub fn contains(&self, session_id: &Uuid) -> bool {
+        self.members.iter().any(|m| m.session_id == *session_id)
+    }
+
+    pub fn state(&self) -> RoomState {
+        self.state
+    }
+
+    /// Toggle ready of player, `None` if not a player
+    pub fn set_ready(&mut self, session_id: &Uuid, ready: bool) -> Option<RosterEntry> {
+        let member = self
+            .members
+            .iter_mut()
+            .find(|m| m.session_id == *session_id && !m.is_spectator())?;
+        member.ready = ready;
+        self.members
+            .iter()
+            .find(|m| m.session_id == *session_id)
+            .map(|member| self.entry(member))
+    }
+
+    /// Move to the state ready toggles call for, returns new state if changed.
+    /// Game starts when enough players are all ready
+    pub fn update_state(&mut self) -> Option<RoomState> {
+        if self.state == RoomState::Playing {
+            return None;
+        }
+        let players = self.players().count();
+        let ready = self.players().filter(|m| m.ready).count();
+        let state = if ready > 0
+            && ready == players
+            && players >= MIN_PLAYERS_TO_START.min(self.settings.max_players)
+        {
+            RoomState::Playing
+        } else if ready > 0 {
+            RoomState::ReadyCheck
+        } else if self.state == RoomState::Finished {
+            RoomState::Finished
+        } else {
+            RoomState::Lobby
+        };
+        if state == self.state {
+            return None;
+        }
+        if state == RoomState::Playing {
+            for member in self.members.iter_mut() {
+                member.ready = false;
+            }
+            self.board.clear();
+            self.seed = Some(Seed::generate());
+        }
+        self.state = state;
+        Some(state)
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    pub fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    /// Every card on board as the member sees it
+    pub fn board_for(&self, session_id: &Uuid) -> BoardState {
+        let state = self.board.state(self.id);
+        state.for_viewer(Some(session_id)).unwrap_or(state)
+    }
+
+    /// Seed of the last game, revealed once the game is over
+    pub fn game_seed(&self) -> Option<GameSeed> {
+        let seed = self.seed.as_ref()?;
+        Some(GameSeed {
+            room_id: self.id,
+            commitment: seed.commitment(),
+            seed: match self.state {
+                RoomState::Playing => None,
+                _ => Some(seed.reveal()),
+            },
+        })
+    }
+
+    /// Random numbers to shuffle deck of player in game being played
+    pub fn shuffle_rng(&self, player: &Uuid) -> Option<ChaCha20Rng> {
+        match self.state {
+            RoomState::Playing => self.seed.as_ref().map(|seed| seed.rng_for(player)),
+            _ => None,
+        }
+    }
+
+    /// End game being played, returns whether it was played
+    pub fn finish(&mut self) -> bool {
+        if self.state != RoomState::Playing {
+            return false;
+        }
+        self.state = RoomState::Finished;
+        true
+    }
+
+    /// Mark room as used now
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Time since room was last used
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    pub fn is_host(&self, session_id: &Uuid) -> bool {
+        self.host == *session_id
+    }
+
+    /// Keep the session out, with the client and name it is a member under
+    pub fn ban(&mut self, session_id: Uuid) {
+        let member = self.members.iter().find(|m| m.session_id == session_id);
+        self.banned.push(Ban {
+            session_id,
+            client_id: member.and_then(|member| member.client_id.clone()),
+            name: member.and_then(|member| member.asked_name.clone()),
+        });
+    }
+
+    /// Whether session, client or name asked for is banned
+    pub fn is_banned(
+        &self,
+        session_id: &Uuid,
+        client_id: Option<&str>,
+        name: Option<&str>,
+    ) -> bool {
+        self.banned
+            .iter()
+            .any(|ban| ban.matches(session_id, client_id, name))
+    }
+
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn is_spectator(&self, session_id: &Uuid) -> bool {
+        self.members
+            .iter()
+            .any(|m| m.session_id == *session_id && m.is_spectator())
+    }
+
+    pub fn allows_spectators(&self) -> bool {
+        self.settings.allow_spectators
+    }
+
+    pub fn is_decks_only(&self) -> bool {
+        self.settings.decks_only
+    }
+
+    /// Seat player at the lowest free seat under a name unique in the room,
+    /// spectators get no seat
+    pub fn add_member(
+        &mut self,
+        session_id: Uuid,
+        name: Option<&str>,
+        client_id: Option<String>,
+        spectator: bool,
+    ) -> RosterEntry {
+        let seat = if spectator {
+            None
+        } else {
+            (0..).find(|seat| self.members.iter().all(|m| m.seat != Some(*seat)))
+        };
+        let unique_name = self.unique_name(&session_id, name.unwrap_or(DEFAULT_NAME));
+        self.touch();
+        self.members.push(Member {
+            session_id,
+            name: unique_name,
+            asked_name: name.map(str::to_string),
+            client_id,
+            seat,
+            ready: false,
+        });
+        self.entry(self.members.last().unwrap())
+    }
+
+    /// Change display name of member, returns the name actually used
+    pub fn rename_member(&mut self, session_id: &Uuid, name: &str) -> Option<String> {
+        let unique_name = self.unique_name(session_id, name);
+        let member = self
+            .members
+            .iter_mut()
+            .find(|m| m.session_id == *session_id)?;
+        member.name = unique_name.clone();
+        member.asked_name = Some(name.to_string());
+        Some(unique_name)
+    }
+
+    /// Add " (2)", " (3)", ... to the name while other member uses it
+    fn unique_name(&self, session_id: &Uuid, name: &str) -> String {
+        let taken = |candidate: &str| {
+            self.members.iter().any(|m| {
+                m.session_id != *session_id && m.name.to_lowercase() == candidate.to_lowercase()
+            })
+        };
+        if !taken(name) {
+            return name.to_string();
+        }
+        (2..)
+            .map(|n| format!("{} ({})", name, n))
+            .find(|candidate| !taken(candidate))
+            .unwrap_or_default()
+    }
+
+    /// Stamp chat message of member and keep it, `None` if not a member
+    pub fn add_chat(&mut self, session_id: &Uuid, text: String) -> Option<ChatEntry> {
+        let member = self.members.iter().find(|m| m.session_id == *session_id)?;
+        let entry = ChatEntry {
+            session_id: *session_id,
+            name: member.name.to_owned(),
+            text,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+        };
+        if self.chat_history.len() == CHAT_HISTORY_SIZE {
+            self.chat_history.pop_front();
+        }
+        self.chat_history.push_back(entry.clone());
+        Some(entry)
+    }
+
+    pub fn chat_history(&self) -> Vec<ChatEntry> {
+        self.chat_history.iter().cloned().collect()
+    }
+
+    pub fn remove_member(&mut self, session_id: &Uuid) -> bool {
+        let len = self.members.len();
+        self.members.retain(|m| m.session_id != *session_id);
+        self.touch();
+        self.members.len() != len
+    }
+
+    /// Make the earliest joined player (or spectator if no player is left)
+    /// host if host has left, returns the new host
+    pub fn migrate_host(&mut self) -> Option<RosterEntry> {
+        if self.contains(&self.host) {
+            return None;
+        }
+        let host = self
+            .players()
+            .next()
+            .or_else(|| self.members.first())?
+            .session_id;
+        self.host = host;
+        self.members
+            .iter()
+            .find(|m| m.session_id == host)
+            .map(|member| self.entry(member))
+    }
+
+    pub fn has_players(&self) -> bool {
+        self.players().next().is_some()
+    }
+
+    /// Whether no more players can join, spectators are not counted
+    pub fn is_full(&self) -> bool {
+        self.players().count() >= self.settings.max_players
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Number message with next seq and keep it for replay,
+    /// returns it for each member except sender
+    pub fn record(&mut self, sender: Option<Uuid>, response: &WsResponse) -> Vec<(Uuid, String)> {
+        self.touch();
+        self.last_seq += 1;
+        let seq = self.last_seq;
+        let message_for = |viewer: Option<&Uuid>| {
+            response
+                .for_viewer(viewer)
+                .as_ref()
+                .unwrap_or(response)
+                .to_json_with_seq(seq)
+        };
+        let message = message_for(None);
+        let views = response
+            .private_owners()
+            .into_iter()
+            .map(|owner| (owner, message_for(Some(&owner))))
+            .filter(|(_, view)| *view != message)
+            .collect();
+        let broadcast = Broadcast {
+            seq,
+            sender,
+            message,
+            views,
+        };
+        let messages = self
+            .members
+            .iter()
+            .filter(|m| Some(m.session_id) != sender)
+            .map(|m| (m.session_id, broadcast.message_for(m).to_string()))
+            .collect();
+        if self.history.len() == ROOM_HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(broadcast);
+        messages
+    }
+
+    /// Messages for session after seq `after`, `None` if some of them are
+    /// already dropped. Messages sent by the session itself are skipped
+    /// same as live broadcasts, so seq seen by a client may have gaps.
+    pub fn replay(&self, session_id: &Uuid, after: u64) -> Option<ReplayedMessages> {
+        let member = self.members.iter().find(|m| m.session_id == *session_id)?;
+        let oldest = self
+            .history
+            .front()
+            .map_or(self.last_seq + 1, |broadcast| broadcast.seq);
+        if after.saturating_add(1) < oldest {
+            return None;
+        }
+        Some(ReplayedMessages {
+            messages: self
+                .history
+                .iter()
+                .filter(|broadcast| broadcast.seq > after && broadcast.sender != Some(*session_id))
+                .map(|broadcast| broadcast.message_for(member).to_string())
+                .collect(),
+            last_seq: self.last_seq,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_room(max_players: usize) -> Room {
+        let settings = RoomSettings {
+            max_players,
+            ..RoomSettings::default()
+        };
+        Room::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "room",
+            settings,
+            generate_invite_code(),
+            None,
+        )
+    }
+
+    #[test]
+    fn unique_name_numbers_taken_names() {
+        let mut room = new_room(4);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        room.add_member(first, Some("Alice"), None, false);
+        assert_eq!(room.unique_name(&second, "Bob"), "Bob");
+        assert_eq!(room.unique_name(&second, "alice"), "alice (2)");
+        room.add_member(second, Some("Alice"), None, false);
+        assert_eq!(room.unique_name(&Uuid::new_v4(), "Alice"), "Alice (3)");
+        // own name is not taken by others
+        assert_eq!(room.unique_name(&first, "Alice"), "Alice");
+    }
+
+    #[test]
+    fn update_state_starts_game_when_every_player_is_ready() {
+        let mut room = new_room(2);
+        let (first, second, spectator) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        room.add_member(first, None, None, false);
+        room.add_member(second, None, None, false);
+        room.add_member(spectator, None, None, true);
+        assert_eq!(room.update_state(), None);
+        room.set_ready(&first, true);
+        assert_eq!(room.update_state(), Some(RoomState::ReadyCheck));
+        assert!(room.set_ready(&spectator, true).is_none());
+        room.set_ready(&second, true);
+        assert_eq!(room.update_state(), Some(RoomState::Playing));
+        assert_eq!(room.update_state(), None);
+        // ready is asked again for next game
+        assert!(room.roster().members.iter().all(|member| !member.ready));
+        let seed = room.game_seed().unwrap();
+        assert!(seed.seed.is_none());
+    }
+
+    #[test]
+    fn update_state_needs_two_players_unless_room_is_for_one() {
+        let mut room = new_room(2);
+        let player = Uuid::new_v4();
+        room.add_member(player, None, None, false);
+        room.set_ready(&player, true);
+        assert_eq!(room.update_state(), Some(RoomState::ReadyCheck));
+
+        let mut solo = new_room(1);
+        solo.add_member(player, None, None, false);
+        solo.set_ready(&player, true);
+        assert_eq!(solo.update_state(), Some(RoomState::Playing));
+    }
+
+    #[test]
+    fn update_state_keeps_finished_until_someone_is_ready() {
+        let mut room = new_room(1);
+        let player = Uuid::new_v4();
+        room.add_member(player, None, None, false);
+        room.set_ready(&player, true);
+        room.update_state();
+        assert!(room.finish());
+        assert!(room.game_seed().unwrap().seed.is_some());
+        assert_eq!(room.update_state(), None);
+        assert_eq!(room.state(), RoomState::Finished);
+    }
+
+    #[test]
+    fn update_state_clears_board_for_new_game() {
+        let mut room = new_room(1);
+        let player = Uuid::new_v4();
+        room.add_member(player, None, None, false);
+        room.board_mut().put(BoardCard {
+            instance_id: 0,
+            card_id: 1,
+            face: "face.png".to_string(),
+            back: "back.png".to_string(),
+            owner: Some(player),
+            position: CardPosition { x: 0.0, y: 0.0 },
+            index: 0,
+            private: false,
+            face_down: false,
+        });
+        assert_eq!(room.board().state(room.id()).cards.len(), 1);
+        room.set_ready(&player, true);
+        room.update_state();
+        assert!(room.board().state(room.id()).cards.is_empty());
+    }
+
+    #[test]
+    fn password_is_checked_against_hash() {
+        let room = Room::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "room",
+            RoomSettings::default(),
+            generate_invite_code(),
+            Some("secret".to_string()),
+        );
+        let plain = |password: &str| JoinPassword::Plain(password.to_string());
+        assert!(room.check_password(Some(&plain("secret"))).is_ok());
+        assert!(room.check_password(Some(&plain("wrong"))).is_err());
+        assert!(room.check_password(None).is_err());
+        let salt = room.password_salt().unwrap();
+        let hashed = JoinPassword::Hashed(hash_password(salt, "secret"));
+        assert!(room.check_password(Some(&hashed)).is_ok());
+        let hashed = JoinPassword::Hashed(hash_password(salt, "wrong"));
+        assert!(room.check_password(Some(&hashed)).is_err());
+        let saved = room.checkpoint("test", &HashMap::new()).unwrap();
+        assert!(!saved.checkpoint.contains("secret"));
+        let (restored, _) = Room::restore(&saved).unwrap();
+        assert!(restored.check_password(Some(&plain("secret"))).is_ok());
+    }
+}
@@ -0,0 +1,696 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::error::BlockingError;
+
+use super::deck_store::{self, DeckCard, DeckStore};
+use super::room_manager::{ChatServer, ChatServerConfig};
+use super::room_store::{DeleteRoom, RoomSaver, SaveRoom};
+use super::Message;
+use super::*;
+
+/// `RoomActor` runs one room, so that busy rooms do not hold up others.
+/// `ChatServer` starts it and is told what lobby needs to know
+pub struct RoomActor {
+    room: Room,
+    /// addresses of connected members
+    addresses: HashMap<Uuid, Recipient<ChatMessage>>,
+    /// resume token of each member, saved with the room
+    resume_tokens: HashMap<Uuid, Uuid>,
+    config: ChatServerConfig,
+    server: Addr<ChatServer>,
+    /// saves room off this thread, room is not saved if `None`
+    saver: Option<Addr<RoomSaver>>,
+    /// where decks are read from, decks can not be played if `None`
+    decks: Option<Arc<dyn DeckStore>>,
+    /// whether room is changed since last saved
+    dirty: bool,
+    /// whether room is closed for good, not stopped by shutdown
+    closed: bool,
+}
+
+impl RoomActor {
+    pub fn new(
+        room: Room,
+        resume_tokens: HashMap<Uuid, Uuid>,
+        config: ChatServerConfig,
+        server: Addr<ChatServer>,
+        saver: Option<Addr<RoomSaver>>,
+        decks: Option<Arc<dyn DeckStore>>,
+    ) -> RoomActor {
+        RoomActor {
+            room,
+            addresses: HashMap::new(),
+            resume_tokens,
+            config,
+            server,
+            saver,
+            decks,
+            dirty: true,
+            closed: false,
+        }
+    }
+
+    /// Save room if it is changed since last saved
+    fn save(&mut self) {
+        let saver = match &self.saver {
+            Some(saver) if self.dirty => saver,
+            _ => return,
+        };
+        match self
+            .room
+            .checkpoint(&self.config.instance_id, &self.resume_tokens)
+        {
+            Ok(saved) => saver.do_send(SaveRoom(saved)),
+            Err(error) => println!("Failed to save room {}: {}", self.room.id(), error),
+        }
+        self.dirty = false;
+    }
+
+    /// Stop room for good, it is not restored after restart
+    fn close(&mut self, ctx: &mut Context<Self>) {
+        self.closed = true;
+        ctx.stop();
+    }
+
+    /// Send message to all connected members except `skip_id`
+    fn send_message(&mut self, response: &WsResponse, skip_id: Option<Uuid>) {
+        self.dirty = true;
+        for (id, message) in self.room.record(skip_id, response) {
+            if let Some(address) = self.addresses.get(&id) {
+                let _ = address.do_send(ChatMessage(message));
+            }
+        }
+    }
+
+    /// Send message only to the member
+    fn send_to_member(&self, session_id: &Uuid, response: &WsResponse) {
+        if let Some(address) = self.addresses.get(session_id) {
+            let _ = address.do_send(ChatMessage(response.to_json()));
+        }
+    }
+
+    /// Send every card on board to the member, with seed of the game
+    fn send_board(&self, session_id: &Uuid) {
+        let snapshot = WsResponse::BoardSnapshot(self.room.board_for(session_id));
+        self.send_to_member(session_id, &snapshot);
+        if let Some(seed) = self.room.game_seed() {
+            self.send_to_member(session_id, &seed_response(seed));
+        }
+    }
+
+    /// Tell every member who is in the room
+    fn send_roster(&mut self) {
+        let roster = self.room.roster();
+        self.send_message(&WsResponse::Roster(roster), None);
+    }
+
+    /// Tell chat server how the room looks in lobby now
+    fn room_changed(&self) {
+        self.server.do_send(RoomChanged {
+            room_id: self.room.id(),
+            info: self.room.info(),
+        });
+    }
+
+    /// Add session to room as player or spectator
+    fn add_member(&mut self, msg: AddMember) -> Result<RoomInfo, ServerError> {
+        let AddMember {
+            session_id,
+            name,
+            client_id,
+            address,
+            resume_token,
+            password,
+            spectate,
+        } = msg;
+        let room_id = self.room.id();
+        if self
+            .room
+            .is_banned(&session_id, client_id.as_deref(), name.as_deref())
+        {
+            return Err(ServerError::new(
+                ErrorCode::Banned,
+                &format!("banned from room {}", room_id),
+            ));
+        }
+        if self.room.contains(&session_id) {
+            return Ok(self.room.member_info());
+        }
+        if self.room.is_locked() {
+            return Err(ServerError::new(
+                ErrorCode::RoomLocked,
+                &format!("room {} is locked", room_id),
+            ));
+        }
+        if spectate && !self.room.allows_spectators() {
+            return Err(ServerError::new(
+                ErrorCode::SpectatorsNotAllowed,
+                &format!("room {} does not allow spectators", room_id),
+            ));
+        }
+        if !spectate && self.room.is_full() {
+            return Err(ServerError::room_full(&room_id));
+        }
+        self.room.check_password(password.as_ref())?;
+        if let Some(address) = address {
+            self.addresses.insert(session_id, address);
+        }
+        self.resume_tokens.insert(session_id, resume_token);
+        let entry = self
+            .room
+            .add_member(session_id, name.as_deref(), client_id, spectate);
+        // send all users in the room except self
+        self.send_message(&WsResponse::SomeoneEnterRoom(entry), Some(session_id));
+        self.send_to_member(
+            &session_id,
+            &WsResponse::ChatHistory(self.room.chat_history()),
+        );
+        self.send_board(&session_id);
+        self.send_roster();
+        self.room_changed();
+        Ok(self.room.member_info())
+    }
+
+    /// Remove member and tell the rest of members,
+    /// room is stopped when nobody is left
+    fn remove_member(&mut self, session_id: &Uuid, ctx: &mut Context<Self>) -> Option<RoomInfo> {
+        if !self.room.remove_member(session_id) {
+            return None;
+        }
+        self.addresses.remove(session_id);
+        self.resume_tokens.remove(session_id);
+        let info = self.room.info();
+        if self.room.is_empty() {
+            self.close(ctx);
+            return Some(info);
+        }
+        let new_host = self.room.migrate_host();
+        self.send_message(
+            &WsResponse::SomeoneLeaveRoom(info.clone()),
+            Some(*session_id),
+        );
+        if let Some(host) = new_host {
+            self.send_message(&WsResponse::HostChanged(host), None);
+        }
+        self.send_roster();
+        // game can not go on without players, spectators are told how it was shuffled
+        if !self.room.has_players() && self.room.finish() {
+            self.send_message(&WsResponse::RoomState(self.room.member_info()), None);
+            self.reveal_seed();
+        }
+        // the rest of players may be all ready
+        self.update_state();
+        self.room_changed();
+        Some(info)
+    }
+
+    /// Move room to the state ready toggles call for and tell every member
+    fn update_state(&mut self) {
+        if self.room.update_state().is_none() {
+            return;
+        }
+        let info = self.room.member_info();
+        let response = match info.state {
+            RoomState::Playing => WsResponse::StartGame(info),
+            _ => WsResponse::RoomState(info),
+        };
+        self.send_message(&response, None);
+        if let (RoomState::Playing, Some(seed)) = (self.room.state(), self.room.game_seed()) {
+            self.send_message(&WsResponse::SeedCommitted(seed), None);
+        }
+    }
+
+    /// Toggle ready of player
+    fn set_ready(&mut self, session_id: &Uuid, ready: bool) -> Result<RosterEntry, ServerError> {
+        if !self.room.contains(session_id) {
+            return Err(ServerError::not_in_room());
+        }
+        if self.room.state() == RoomState::Playing {
+            return Err(ServerError::new(
+                ErrorCode::InvalidState,
+                "game is already being played",
+            ));
+        }
+        let entry = self.room.set_ready(session_id, ready).ok_or_else(|| {
+            ServerError::new(ErrorCode::NotPlayer, "spectators can not get ready")
+        })?;
+        self.send_roster();
+        self.update_state();
+        self.room_changed();
+        Ok(entry)
+    }
+
+    /// End game being played
+    fn finish_game(&mut self, session_id: &Uuid) -> Result<RoomInfo, ServerError> {
+        self.check_host(session_id)?;
+        if !self.room.finish() {
+            return Err(ServerError::new(
+                ErrorCode::InvalidState,
+                "game is not being played",
+            ));
+        }
+        let info = self.room.member_info();
+        self.send_message(&WsResponse::FinishGame(info.clone()), Some(*session_id));
+        self.reveal_seed();
+        self.room_changed();
+        Ok(info)
+    }
+
+    /// Tell every member the seed of game which has just ended,
+    /// however it ended
+    fn reveal_seed(&mut self) {
+        if let Some(seed) = self.room.game_seed() {
+            self.send_message(&WsResponse::SeedRevealed(seed), None);
+        }
+    }
+
+    /// Check the session is a player of the room
+    fn check_player(&self, session_id: &Uuid) -> Result<(), ServerError> {
+        if !self.room.contains(session_id) {
+            return Err(ServerError::not_in_room());
+        }
+        if self.room.is_spectator(session_id) {
+            return Err(ServerError::new(
+                ErrorCode::NotPlayer,
+                "spectators can not touch cards",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the session is a player of game being played
+    fn check_playing(&self, session_id: &Uuid) -> Result<(), ServerError> {
+        self.check_player(session_id)?;
+        if self.room.state() != RoomState::Playing {
+            return Err(ServerError::new(
+                ErrorCode::InvalidState,
+                "cards can be touched only while playing",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send cards info to other members, spectators can not.
+    /// Cards are put on board too, so that members joining later see them.
+    /// Players dealt a deck can only move its cards.
+    /// Old clients send cards without getting ready, in any state
+    fn send_cards(&mut self, msg: &Message) -> Result<(), ServerError> {
+        if msg.legacy {
+            self.check_player(&msg.id)?;
+        } else {
+            self.check_playing(&msg.id)?;
+        }
+        if self.room.is_decks_only() {
+            return Err(ServerError::new(
+                ErrorCode::InvalidState,
+                "cards come only from decks in this room",
+            ));
+        }
+        if self.room.board().deck_of(&msg.id).is_some() {
+            return Err(ServerError::new(
+                ErrorCode::InvalidState,
+                "cards come only from the deck dealt in this game",
+            ));
+        }
+        if msg.first {
+            self.room.board_mut().deal(msg.id, &msg.cards.cards);
+        } else {
+            self.room.board_mut().update(msg.id, &msg.cards.cards);
+        }
+        let response = if msg.first {
+            WsResponse::FirstCardsInfo(msg.cards.clone())
+        } else {
+            WsResponse::CardsInfo(msg.cards.clone())
+        };
+        self.send_message(&response, Some(msg.id));
+        Ok(())
+    }
+
+    /// Move cards on board and tell other members where they are now
+    fn move_cards(&mut self, msg: &MoveCards) -> Result<BoardState, ServerError> {
+        self.check_playing(&msg.session_id)?;
+        let cards = self
+            .room
+            .board_mut()
+            .apply(&msg.session_id, &msg.moves)
+            .map_err(|instance_id| {
+                ServerError::new(
+                    ErrorCode::InvalidMove,
+                    &format!("card {} can not be moved", instance_id),
+                )
+            })?;
+        let state = BoardState {
+            room_id: self.room.id(),
+            version: self.room.board().version(),
+            cards,
+        };
+        self.send_message(&WsResponse::CardsMoved(state.clone()), Some(msg.session_id));
+        Ok(state.for_viewer(Some(&msg.session_id)).unwrap_or(state))
+    }
+
+    /// Check the player can be dealt a deck, once per game
+    fn check_deck(&self, session_id: &Uuid) -> Result<(), ServerError> {
+        self.check_playing(session_id)?;
+        if self.room.board().deck_of(session_id).is_some() {
+            return Err(ServerError::new(
+                ErrorCode::InvalidState,
+                "deck is already dealt in this game",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Put every card of player's deck on board
+    fn play_deck(&mut self, msg: &PlayDeck, deck: &[DeckCard]) -> Result<BoardState, ServerError> {
+        // game may have ended or deck been dealt while deck was loaded
+        self.check_deck(&msg.session_id)?;
+        let mut rng = self
+            .room
+            .shuffle_rng(&msg.session_id)
+            .ok_or_else(ServerError::internal)?;
+        let cards = self
+            .room
+            .board_mut()
+            .deal_deck(msg.session_id, msg.deck_id, deck, &mut rng);
+        let state = BoardState {
+            room_id: self.room.id(),
+            version: self.room.board().version(),
+            cards,
+        };
+        self.send_message(&WsResponse::DeckDealt(state.clone()), Some(msg.session_id));
+        // the player does not see faces of own deck either
+        Ok(state.for_viewer(Some(&msg.session_id)).unwrap_or(state))
+    }
+
+    fn check_host(&self, session_id: &Uuid) -> Result<(), ServerError> {
+        if self.room.is_host(session_id) {
+            Ok(())
+        } else if self.room.contains(session_id) {
+            Err(ServerError::new(
+                ErrorCode::NotHost,
+                "only room host can do this",
+            ))
+        } else {
+            Err(ServerError::not_in_room())
+        }
+    }
+
+    /// Remove member, telling the member first.
+    /// Banned sessions can not join again even if they are not in the room,
+    /// nor can the client from a new connection
+    fn kick_member(
+        &mut self,
+        msg: &Kick,
+        ctx: &mut Context<Self>,
+    ) -> Result<KickInfo, ServerError> {
+        self.check_host(&msg.session_id)?;
+        if msg.target == msg.session_id {
+            return Err(ServerError::new(
+                ErrorCode::InvalidTarget,
+                "host can not kick itself",
+            ));
+        }
+        let is_member = self.room.contains(&msg.target);
+        if !is_member && !msg.ban {
+            return Err(ServerError::new(
+                ErrorCode::MemberNotFound,
+                &format!("{} is not in room", msg.target),
+            ));
+        }
+        if msg.ban {
+            self.room.ban(msg.target);
+        }
+        let info = KickInfo {
+            room_id: self.room.id(),
+            session_id: msg.target,
+            banned: msg.ban,
+        };
+        if is_member {
+            self.send_to_member(&msg.target, &WsResponse::Kicked(info.clone()));
+            // chat server forgets the room first, so lobby is sent to the member again
+            self.server.do_send(MemberRemoved {
+                room_id: self.room.id(),
+                session_id: msg.target,
+            });
+            self.remove_member(&msg.target, ctx);
+        }
+        Ok(info)
+    }
+
+    /// Lock room against new members or unlock it
+    fn lock_room(&mut self, session_id: &Uuid, locked: bool) -> Result<RoomInfo, ServerError> {
+        self.check_host(session_id)?;
+        self.room.set_locked(locked);
+        let info = self.room.member_info();
+        self.send_message(&WsResponse::LockRoom(info.clone()), Some(*session_id));
+        self.room_changed();
+        Ok(info)
+    }
+
+    /// Send chat message to other members
+    fn send_chat(&mut self, session_id: &Uuid, text: &str) -> Result<ChatEntry, ServerError> {
+        let text = room::validate_chat(text)?;
+        let entry = self
+            .room
+            .add_chat(session_id, text)
+            .ok_or_else(ServerError::not_in_room)?;
+        self.send_message(&WsResponse::Chat(entry.clone()), Some(*session_id));
+        Ok(entry)
+    }
+
+    /// Why room should be closed, `None` if it is still in use
+    fn expiry_reason(&self) -> Option<&'static str> {
+        let idle_for = self.room.idle_for();
+        if self.addresses.is_empty() && idle_for >= self.config.empty_room_timeout {
+            Some("nobody is connected")
+        } else if idle_for >= self.config.idle_room_timeout {
+            Some("room is idle")
+        } else {
+            None
+        }
+    }
+
+    /// Close room if it is expired, telling members first
+    fn sweep(&mut self, ctx: &mut Context<Self>) {
+        let reason = match self.expiry_reason() {
+            Some(reason) => reason,
+            None => return,
+        };
+        println!("Closing room {}: {}", self.room.id(), reason);
+        if self.room.finish() {
+            self.reveal_seed();
+        }
+        let closed = WsResponse::RoomClosed(RoomClosed {
+            room_id: self.room.id(),
+            reason: reason.to_string(),
+        });
+        for address in self.addresses.values() {
+            let _ = address.do_send(ChatMessage(closed.to_json()));
+        }
+        self.close(ctx);
+    }
+}
+
+/// Cards of deck loaded by store, error if it has none or too many
+fn loaded_deck(
+    deck_id: i32,
+    loaded: Result<Option<Vec<DeckCard>>, BlockingError<String>>,
+) -> Result<Vec<DeckCard>, ServerError> {
+    match loaded {
+        Ok(Some(deck)) if deck.iter().any(|card| card.num > 0) => {
+            deck_store::check_deck_size(deck_id, &deck)?;
+            Ok(deck)
+        }
+        Ok(_) => Err(ServerError::new(
+            ErrorCode::DeckNotFound,
+            &format!("deck {} is not found or empty", deck_id),
+        )),
+        Err(error) => {
+            println!("Failed to load deck {}: {}", deck_id, error);
+            Err(ServerError::internal())
+        }
+    }
+}
+
+/// Seed is committed while game is played and revealed after
+fn seed_response(seed: GameSeed) -> WsResponse {
+    match seed.seed {
+        Some(_) => WsResponse::SeedRevealed(seed),
+        None => WsResponse::SeedCommitted(seed),
+    }
+}
+
+impl Actor for RoomActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.config.sweep_interval, |act, ctx| act.sweep(ctx));
+        ctx.run_interval(self.config.checkpoint_interval, |act, _| act.save());
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        match &self.saver {
+            Some(saver) if self.closed => saver.do_send(DeleteRoom(self.room.id())),
+            _ => self.save(),
+        }
+        // members are removed from the room on chat server
+        self.server.do_send(RoomStopped {
+            room_id: self.room.id(),
+        });
+    }
+}
+
+/// Handler for AddMember message.
+impl Handler<AddMember> for RoomActor {
+    type Result = MessageResult<AddMember>;
+
+    fn handle(&mut self, msg: AddMember, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.add_member(msg))
+    }
+}
+
+/// Handler for RemoveMember message.
+impl Handler<RemoveMember> for RoomActor {
+    type Result = MessageResult<RemoveMember>;
+
+    fn handle(&mut self, msg: RemoveMember, ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.remove_member(&msg.session_id, ctx))
+    }
+}
+
+/// Handler for RenameMember message.
+impl Handler<RenameMember> for RoomActor {
+    type Result = MessageResult<RenameMember>;
+
+    fn handle(&mut self, msg: RenameMember, _: &mut Context<Self>) -> Self::Result {
+        let name = self.room.rename_member(&msg.session_id, &msg.name);
+        if name.is_some() {
+            self.send_roster();
+        }
+        MessageResult(name)
+    }
+}
+
+/// Handler for MemberAddress message.
+impl Handler<MemberAddress> for RoomActor {
+    type Result = MessageResult<MemberAddress>;
+
+    fn handle(&mut self, msg: MemberAddress, _: &mut Context<Self>) -> Self::Result {
+        if !self.room.contains(&msg.session_id) {
+            return MessageResult(None);
+        }
+        if self.resume_tokens.insert(msg.session_id, msg.resume_token) != Some(msg.resume_token) {
+            self.dirty = true;
+        }
+        match msg.address {
+            Some(address) => {
+                self.addresses.insert(msg.session_id, address);
+                // board may have changed more than replay can tell
+                self.send_board(&msg.session_id);
+            }
+            None => {
+                self.addresses.remove(&msg.session_id);
+                // room waits for empty timeout from now if nobody else is connected
+                self.room.touch();
+            }
+        }
+        MessageResult(Some(self.room.member_info()))
+    }
+}
+
+/// Handler for Message message.
+impl Handler<Message> for RoomActor {
+    type Result = MessageResult<Message>;
+
+    fn handle(&mut self, msg: Message, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.send_cards(&msg))
+    }
+}
+
+/// Handler for MoveCards message.
+impl Handler<MoveCards> for RoomActor {
+    type Result = MessageResult<MoveCards>;
+
+    fn handle(&mut self, msg: MoveCards, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.move_cards(&msg))
+    }
+}
+
+/// Handler for PlayDeck message.
+///
+/// Deck is loaded on blocking thread pool, so that the room keeps handling
+/// other messages meanwhile
+impl Handler<PlayDeck> for RoomActor {
+    type Result = ResponseActFuture<Self, Result<BoardState, ServerError>>;
+
+    fn handle(&mut self, msg: PlayDeck, _: &mut Context<Self>) -> Self::Result {
+        let store = match self
+            .check_deck(&msg.session_id)
+            .and_then(|_| self.decks.clone().ok_or_else(ServerError::internal))
+        {
+            Ok(store) => store,
+            Err(error) => return Box::pin(fut::ready(Err(error))),
+        };
+        let deck_id = msg.deck_id;
+        let load = web::block(move || store.load(deck_id).map_err(|error| error.to_string()));
+        Box::pin(load.into_actor(self).map(move |loaded, act, _| {
+            let deck = loaded_deck(msg.deck_id, loaded)?;
+            act.play_deck(&msg, &deck)
+        }))
+    }
+}
+
+/// Handler for Replay message.
+impl Handler<Replay> for RoomActor {
+    type Result = MessageResult<Replay>;
+
+    fn handle(&mut self, msg: Replay, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.room.replay(&msg.session_id, msg.after))
+    }
+}
+
+/// Handler for SendChat message.
+impl Handler<SendChat> for RoomActor {
+    type Result = MessageResult<SendChat>;
+
+    fn handle(&mut self, msg: SendChat, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.send_chat(&msg.session_id, &msg.text))
+    }
+}
+
+/// Handler for Kick message.
+impl Handler<Kick> for RoomActor {
+    type Result = MessageResult<Kick>;
+
+    fn handle(&mut self, msg: Kick, ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.kick_member(&msg, ctx))
+    }
+}
+
+/// Handler for SetReady message.
+impl Handler<SetReady> for RoomActor {
+    type Result = MessageResult<SetReady>;
+
+    fn handle(&mut self, msg: SetReady, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.set_ready(&msg.session_id, msg.ready))
+    }
+}
+
+/// Handler for FinishGame message.
+impl Handler<FinishGame> for RoomActor {
+    type Result = MessageResult<FinishGame>;
+
+    fn handle(&mut self, msg: FinishGame, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.finish_game(&msg.session_id))
+    }
+}
+
+/// Handler for Lock message.
+impl Handler<Lock> for RoomActor {
+    type Result = MessageResult<Lock>;
+
+    fn handle(&mut self, msg: Lock, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.lock_room(&msg.session_id, msg.locked))
+    }
+}
@@ -1,106 +1,1044 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use super::Message;
+use futures::channel::oneshot;
+
+use super::deck_store::DeckStore;
+use super::matchmaking::{self, MATCH_ROOM_NAME};
+use super::message_bus::{BusBody, BusMessage, LocalBus, MessageBus};
+use super::remote_room::{RemoteRoom, RemoteSession, ReplyArrived, RoomLink, RoomRequest};
+use super::room::JoinPassword;
+use super::room_actor::RoomActor;
+use super::room_store::{DeleteRoom, RoomSaver, RoomStore};
 use super::*;
 
-/// `ChatServer` manages chat rooms and responsible for coordinating chat
-/// session. implementation is super primitive
+/// Settings of `ChatServer`
+#[derive(Debug, Clone)]
+pub struct ChatServerConfig {
+    /// How long a disconnected session keeps its rooms waiting for resume
+    pub resume_grace: Duration,
+    /// How long a room without connected members is kept
+    pub empty_room_timeout: Duration,
+    /// How long a room without any activity is kept
+    pub idle_room_timeout: Duration,
+    /// How often expired rooms and sessions are looked for
+    pub sweep_interval: Duration,
+    /// How many threads run room actors
+    pub room_threads: usize,
+    /// How often changed rooms are saved
+    pub checkpoint_interval: Duration,
+    /// Name of this instance, unique among instances sharing message bus
+    /// and database
+    pub instance_id: String,
+    /// How long a session waits in matchmaking queue
+    pub matchmaking_timeout: Duration,
+}
+
+impl Default for ChatServerConfig {
+    fn default() -> ChatServerConfig {
+        ChatServerConfig {
+            resume_grace: Duration::from_secs(30),
+            empty_room_timeout: Duration::from_secs(5 * 60),
+            idle_room_timeout: Duration::from_secs(60 * 60),
+            sweep_interval: Duration::from_secs(60),
+            room_threads: 4,
+            checkpoint_interval: Duration::from_secs(5),
+            instance_id: "default".to_string(),
+            matchmaking_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ChatServerConfig {
+    /// Read settings from environment variables (or `.env`),
+    /// unset ones fall back to default
+    pub fn from_env() -> ChatServerConfig {
+        let default = ChatServerConfig::default();
+        ChatServerConfig {
+            resume_grace: env_secs("RESUME_GRACE_SECS").unwrap_or(default.resume_grace),
+            empty_room_timeout: env_secs("EMPTY_ROOM_TIMEOUT_SECS")
+                .unwrap_or(default.empty_room_timeout),
+            idle_room_timeout: env_secs("IDLE_ROOM_TIMEOUT_SECS")
+                .unwrap_or(default.idle_room_timeout),
+            sweep_interval: env_secs("SWEEP_INTERVAL_SECS").unwrap_or(default.sweep_interval),
+            room_threads: env_parse("ROOM_THREADS")
+                .filter(|threads| *threads > 0)
+                .unwrap_or(default.room_threads),
+            checkpoint_interval: env_secs("CHECKPOINT_INTERVAL_SECS")
+                .unwrap_or(default.checkpoint_interval),
+            instance_id: env_instance_id().unwrap_or(default.instance_id),
+            matchmaking_timeout: env_secs("MATCHMAKING_TIMEOUT_SECS")
+                .unwrap_or(default.matchmaking_timeout),
+        }
+    }
+}
+
+/// `INSTANCE_ID`, which must stay the same across restarts, rooms saved under
+/// it are restored. Instances sharing bus tell each other apart by it, so it
+/// must be set for `MESSAGE_BUS=postgres`
+fn env_instance_id() -> Option<String> {
+    match dotenv::var("INSTANCE_ID") {
+        Ok(id) if id.trim().is_empty() => panic!("INSTANCE_ID must not be empty"),
+        Ok(id) => Some(id),
+        Err(_) if dotenv::var("MESSAGE_BUS").ok().as_deref() == Some("postgres") => {
+            panic!("INSTANCE_ID must be set for MESSAGE_BUS=postgres")
+        }
+        Err(_) => None,
+    }
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+    dotenv::var(key).ok().and_then(|value| value.parse().ok())
+}
+
+fn env_secs(key: &str) -> Option<Duration> {
+    env_parse(key).map(Duration::from_secs)
+}
+
+/// Room actor and what lobby knows about the room
+struct RoomHandle {
+    addr: Addr<RoomActor>,
+    /// room info last reported by the room
+    info: RoomInfo,
+    invite_code: String,
+    /// told to other instances, so that they hash passwords before asking to join
+    password_salt: Option<String>,
+}
+
+/// Room on another instance, as it announces over message bus
+struct RemoteRoomHandle {
+    addr: Addr<RemoteRoom>,
+    info: RoomInfo,
+    invite_code: String,
+    /// salt to hash password with, `None` if room has no password
+    password_salt: Option<String>,
+    /// last time the room was announced
+    last_seen: Instant,
+}
+
+/// `ChatServer` manages sessions and lobby, creates rooms and routes
+/// sessions to them. Each room runs as its own `RoomActor` on a pool of
+/// threads, so a room which stops (even by panic) does not stop the others.
+/// Instances share rooms and lobby over message bus
 pub struct ChatServer {
+    config: ChatServerConfig,
     sessions: HashMap<Uuid, Session>,
-    rooms: HashMap<Uuid, Room>,
+    rooms: HashMap<Uuid, RoomHandle>,
+    /// room id for each invite code
+    invite_codes: HashMap<String, Uuid>,
+    /// threads room actors run on
+    arbiters: Vec<Arbiter>,
+    /// index of arbiter next room runs on
+    next_arbiter: usize,
+    /// where rooms are saved, rooms are lost on restart if `None`
+    store: Option<Arc<dyn RoomStore>>,
+    /// saves rooms to `store` off room threads, started with chat server
+    saver: Option<Addr<RoomSaver>>,
+    /// where decks are read from, decks can not be played if `None`
+    decks: Option<Arc<dyn DeckStore>>,
+    bus: Arc<dyn MessageBus>,
+    /// rooms on other instances
+    remote_rooms: HashMap<Uuid, RemoteRoomHandle>,
+    /// instance and room of members connected to other instances
+    remote_members: HashMap<Uuid, (String, Uuid)>,
+    /// sessions waiting for match, only sessions connected to this
+    /// instance are matched with each other
+    queue: matchmaking::Queue,
 }
 
-impl Default for ChatServer {
-    fn default() -> ChatServer {
+impl ChatServer {
+    pub fn new(config: ChatServerConfig) -> ChatServer {
         ChatServer {
+            queue: matchmaking::Queue::new(config.matchmaking_timeout),
+            config,
             sessions: HashMap::new(),
             rooms: HashMap::new(),
+            invite_codes: HashMap::new(),
+            arbiters: Vec::new(),
+            next_arbiter: 0,
+            store: None,
+            saver: None,
+            decks: None,
+            bus: Arc::new(LocalBus::default()),
+            remote_rooms: HashMap::new(),
+            remote_members: HashMap::new(),
         }
     }
-}
 
-impl ChatServer {
-    /// Send message to all users in the room
-    fn send_message(&self, room: &Uuid, message: &str, skip_id: Uuid) {
-        if let Some(Room { name: _, members }) = self.rooms.get(room) {
-            for id in members {
-                if *id != skip_id {
-                    if let Some(Session { address }) = self.sessions.get(id) {
-                        let _ = address.do_send(ChatMessage(message.to_owned()));
-                    }
-                }
-            }
+    /// Share rooms and lobby with other instances over `bus`
+    pub fn with_bus(mut self, bus: Arc<dyn MessageBus>) -> ChatServer {
+        self.bus = bus;
+        self
+    }
+
+    /// Publish message to `to` instance, or every instance if `None`
+    fn publish(&self, to: Option<String>, body: BusBody) {
+        let message = BusMessage {
+            from: self.config.instance_id.clone(),
+            to,
+            body,
+        };
+        if let Err(error) = self.bus.publish(&message) {
+            println!("Failed to publish to message bus: {}", error);
         }
     }
 
-    fn send_all(&self, message: &str) {
-        for (_session_id, session) in &self.sessions {
-            let _ = session.address.do_send(ChatMessage(message.to_owned()));
+    /// Tell other instances the room is in lobby
+    fn publish_room(&self, room_id: &Uuid) {
+        if let Some(handle) = self.rooms.get(room_id) {
+            self.publish(
+                None,
+                BusBody::RoomListed {
+                    info: handle.info.clone(),
+                    invite_code: handle.invite_code.clone(),
+                    password_salt: handle.password_salt.clone(),
+                },
+            );
         }
     }
 
-    fn update_room_list(&self) {
-        let mut rooms = Vec::new();
+    /// Save rooms to `store` and restore them from it on start
+    pub fn with_store(mut self, store: Arc<dyn RoomStore>) -> ChatServer {
+        self.store = Some(store);
+        self
+    }
+
+    /// Let players put their decks stored in `decks` on board
+    pub fn with_decks(mut self, decks: Arc<dyn DeckStore>) -> ChatServer {
+        self.decks = Some(decks);
+        self
+    }
 
-        // Get room list
-        for (room_id, Room { name, members }) in &self.rooms {
-            let room = RoomInfo {
-                id: room_id.clone(),
-                name: name.to_owned(),
-                num: members.len(),
+    /// Send message only to the session
+    fn send_to_session(&self, session_id: &Uuid, response: &WsResponse) {
+        if let Some(Session {
+            address: Some(address),
+            ..
+        }) = self.sessions.get(session_id)
+        {
+            let _ = address.do_send(ChatMessage(response.to_json()));
+        }
+    }
+
+    /// Tell lobby subscribers rooms changed since last told.
+    /// Sessions playing game or disconnected catch up later
+    fn update_lobby(&mut self) {
+        let rooms = self.public_rooms();
+        let playing: Vec<Uuid> = self
+            .room_infos()
+            .filter(|info| info.state == RoomState::Playing)
+            .map(|info| info.id)
+            .collect();
+        for session in self.sessions.values_mut() {
+            let (address, subscription) = match session {
+                Session {
+                    address: Some(address),
+                    lobby: Some(subscription),
+                    ..
+                } => (address, subscription),
+                _ => continue,
             };
-            rooms.push(room);
+            if matches!(session.room, Some(room_id) if playing.contains(&room_id)) {
+                continue;
+            }
+            for response in subscription.update(&rooms) {
+                let _ = address.do_send(ChatMessage(response.to_json()));
+            }
+        }
+    }
+
+    /// Rooms of every instance
+    fn room_infos(&self) -> impl Iterator<Item = &RoomInfo> {
+        self.rooms
+            .values()
+            .map(|handle| &handle.info)
+            .chain(self.remote_rooms.values().map(|handle| &handle.info))
+    }
+
+    /// Rooms shown in lobby
+    fn public_rooms(&self) -> Vec<RoomInfo> {
+        self.room_infos()
+            .filter(|info| !info.settings.private)
+            .cloned()
+            .collect()
+    }
+
+    fn room_list(&self) -> RoomInfoList {
+        let rooms = self.public_rooms();
+        RoomInfoList {
+            total: rooms.len(),
+            rooms,
         }
-        self.send_all(&RoomInfoList { rooms: rooms }.get_json_data(Status::Ok, Event::GetRoomList));
     }
 
-    fn add_room(&mut self, session_id: &Uuid, room_name: &str) -> MessageResult<Create> {
+    /// Subscribe session to lobby, replacing its query if already subscribed
+    fn subscribe(
+        &mut self,
+        session_id: &Uuid,
+        query: RoomQuery,
+    ) -> Result<RoomInfoList, ServerError> {
+        let rooms = self.public_rooms();
+        let session = self
+            .sessions
+            .get_mut(session_id)
+            .ok_or_else(ServerError::internal)?;
+        let mut subscription = lobby::Subscription::new(query);
+        let page = subscription.page(&rooms);
+        session.lobby = Some(subscription);
+        Ok(page)
+    }
+
+    fn unsubscribe(&mut self, session_id: &Uuid) -> Result<(), ServerError> {
+        self.sessions
+            .get_mut(session_id)
+            .ok_or_else(ServerError::internal)?
+            .lobby = None;
+        Ok(())
+    }
+
+    /// Room which the session is member of
+    fn session_room(&self, session_id: &Uuid) -> Option<RoomLink> {
+        self.sessions
+            .get(session_id)
+            .and_then(|session| session.room)
+            .and_then(|room_id| self.room_link(&room_id))
+    }
+
+    /// Room on this instance or another one
+    fn room_link(&self, room_id: &Uuid) -> Option<RoomLink> {
+        match (self.rooms.get(room_id), self.remote_rooms.get(room_id)) {
+            (Some(handle), _) => Some(RoomLink::Local(handle.addr.clone())),
+            (None, Some(handle)) => Some(RoomLink::Remote(handle.addr.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// Invite code no other room uses
+    fn new_invite_code(&self) -> String {
+        loop {
+            let code = room::generate_invite_code();
+            if self.find_room_by_code(&code).is_err() {
+                return code;
+            }
+        }
+    }
+
+    /// Start actor for room hosted by the session, the host joins it after
+    fn add_room(
+        &mut self,
+        session_id: Uuid,
+        room_name: &str,
+        settings: RoomSettings,
+        password: Option<String>,
+        ctx: &mut Context<Self>,
+    ) -> Result<Uuid, ServerError> {
+        room::validate_settings(&settings)?;
+        if let Some(password) = &password {
+            room::validate_password(password)?;
+        }
+        let room = Room::new(
+            Uuid::new_v4(),
+            session_id,
+            room_name,
+            settings,
+            self.new_invite_code(),
+            password,
+        );
+        Ok(self.start_room(room, HashMap::new(), ctx))
+    }
+
+    /// Start actor of the room on the next thread
+    fn start_room(
+        &mut self,
+        room: Room,
+        resume_tokens: HashMap<Uuid, Uuid>,
+        ctx: &mut Context<Self>,
+    ) -> Uuid {
+        let room_id = room.id();
+        let info = room.info();
+        let invite_code = room.invite_code().to_string();
+        let password_salt = room.password_salt().map(str::to_string);
+        self.invite_codes.insert(invite_code.clone(), room_id);
+        let config = self.config.clone();
+        let server = ctx.address();
+        let saver = self.saver.clone();
+        let decks = self.decks.clone();
+        let arbiter = &self.arbiters[self.next_arbiter % self.arbiters.len()];
+        self.next_arbiter += 1;
+        let addr = RoomActor::start_in_arbiter(arbiter, move |_| {
+            RoomActor::new(room, resume_tokens, config, server, saver, decks)
+        });
         self.rooms.insert(
-            // room id becomes room host session id
-            session_id.clone(),
-            Room {
-                name: room_name.to_owned(),
-                members: HashSet::new(),
+            room_id,
+            RoomHandle {
+                addr,
+                info,
+                invite_code,
+                password_salt,
             },
         );
-        MessageResult(CreateRoom {
-            room_id: session_id.clone(),
-            room_name: room_name.to_owned(),
-        })
+        room_id
+    }
+
+    /// Start rooms saved before restart. Their members are kept as
+    /// disconnected sessions, which can resume until grace period passes
+    fn restore_rooms(&mut self, ctx: &mut Context<Self>) {
+        let instance = &self.config.instance_id;
+        let saved_rooms = match self.store.as_ref().map(|store| store.load(instance)) {
+            Some(Ok(saved_rooms)) => saved_rooms,
+            Some(Err(error)) => {
+                println!("Failed to load rooms: {}", error);
+                return;
+            }
+            None => return,
+        };
+        for saved in &saved_rooms {
+            let (room, resume_tokens) = match Room::restore(saved) {
+                Ok(restored) => restored,
+                Err(error) => {
+                    println!("Failed to restore room {}: {}", saved.id, error);
+                    continue;
+                }
+            };
+            for (session_id, resume_token) in &resume_tokens {
+                self.sessions.insert(
+                    *session_id,
+                    Session {
+                        address: None,
+                        left: None,
+                        resume_token: *resume_token,
+                        disconnected_at: Some(Instant::now()),
+                        room: Some(room.id()),
+                        name: room.member_name(session_id),
+                        client_id: None,
+                        lobby: None,
+                    },
+                );
+            }
+            self.start_room(room, resume_tokens, ctx);
+        }
+        println!("Restored {} rooms", self.rooms.len());
+    }
+
+    /// Room to join by id, private rooms are treated as missing
+    fn find_room(&self, session_id: &Uuid, room_id: Uuid) -> Result<Uuid, ServerError> {
+        let current = self
+            .sessions
+            .get(session_id)
+            .and_then(|session| session.room);
+        match self.room_infos().find(|info| info.id == room_id) {
+            Some(info) if !info.settings.private || current == Some(room_id) => Ok(room_id),
+            _ => Err(ServerError::room_not_found(&room_id)),
+        }
+    }
+
+    /// Room to join by invite code, case insensitive
+    fn find_room_by_code(&self, invite_code: &str) -> Result<Uuid, ServerError> {
+        let invite_code = invite_code.trim().to_uppercase();
+        self.invite_codes
+            .get(&invite_code)
+            .copied()
+            .or_else(|| {
+                self.remote_rooms
+                    .values()
+                    .find(|handle| handle.invite_code == invite_code)
+                    .map(|handle| handle.info.id)
+            })
+            .ok_or_else(|| {
+                ServerError::new(
+                    ErrorCode::RoomNotFound,
+                    &format!("no room for invite code {:?}", invite_code),
+                )
+            })
+    }
+
+    /// Ask room to add session, then leave the room the session was in
+    fn enter_room(
+        &mut self,
+        session_id: Uuid,
+        room_id: Uuid,
+        password: Option<String>,
+        spectate: bool,
+    ) -> ResponseActFuture<Self, Result<JoinedRoom, ServerError>> {
+        let (addr, session) = match (self.room_link(&room_id), self.sessions.get(&session_id)) {
+            (Some(addr), Some(session)) => (addr, session),
+            _ => return Box::pin(fut::ready(Err(ServerError::room_not_found(&room_id)))),
+        };
+        let request = AddMember {
+            session_id,
+            name: session.name.clone(),
+            client_id: session.client_id.clone(),
+            address: session.address.clone(),
+            resume_token: session.resume_token,
+            password: self.join_password(&room_id, password),
+            spectate,
+        };
+        Box::pin(
+            addr.send(request)
+                .into_actor(self)
+                .map(move |res, act, _| match res {
+                    Ok(Ok(info)) => {
+                        act.joined_room(session_id, room_id);
+                        Ok(JoinedRoom { info, addr })
+                    }
+                    Ok(Err(error)) => Err(error),
+                    Err(_) => {
+                        act.drop_room(&room_id);
+                        Err(ServerError::room_not_found(&room_id))
+                    }
+                }),
+        )
+    }
+
+    /// Password to ask room to join with. Rooms on other instances
+    /// are sent its hash, so that password does not go over message bus
+    fn join_password(&self, room_id: &Uuid, password: Option<String>) -> Option<JoinPassword> {
+        let password = password?;
+        if self.rooms.contains_key(room_id) {
+            return Some(JoinPassword::Plain(password));
+        }
+        let salt = self.remote_rooms.get(room_id)?.password_salt.as_ref()?;
+        Some(JoinPassword::Hashed(room::hash_password(salt, &password)))
+    }
+
+    /// Record session is in the room, leaving the room it was in
+    /// and matchmaking queue
+    fn joined_room(&mut self, session_id: Uuid, room_id: Uuid) {
+        self.queue.leave(&session_id);
+        let leaving = match self.sessions.get_mut(&session_id) {
+            Some(session) => session
+                .room
+                .replace(room_id)
+                .filter(|previous| *previous != room_id),
+            // session is removed while joining
+            None => Some(room_id),
+        };
+        if let Some(addr) = leaving.and_then(|room_id| self.room_link(&room_id)) {
+            addr.do_send(RemoveMember { session_id });
+        }
+    }
+
+    /// Put session in matchmaking queue, and start a room as soon as
+    /// another session waits for the same tag
+    fn matchmake(
+        &mut self,
+        session_id: Uuid,
+        tag: Option<String>,
+        ctx: &mut Context<Self>,
+    ) -> Result<MatchTicket, ServerError> {
+        let tag = tag
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty());
+        room::validate_settings(&matchmaking::room_settings(&tag))?;
+        if !self.sessions.contains_key(&session_id) {
+            return Err(ServerError::internal());
+        }
+        let (sender, matched) = oneshot::channel();
+        let (ticket, info) = self.queue.enter(session_id, tag, sender);
+        ctx.run_later(self.queue.timeout(), move |act, _| {
+            if let Some(entry) = act.queue.expire(&ticket) {
+                let _ = entry.matched.send(Err(ServerError::new(
+                    ErrorCode::MatchmakingTimeout,
+                    "no other player is found, try again later",
+                )));
+            }
+        });
+        if let Some((first, second)) = self.queue.pair(&session_id) {
+            self.start_match(first, second, ctx);
+        }
+        Ok(MatchTicket { info, matched })
+    }
+
+    /// Start room for matched sessions and join both,
+    /// the one which waited longer hosts it
+    fn start_match(
+        &mut self,
+        first: matchmaking::Entry,
+        second: matchmaking::Entry,
+        ctx: &mut Context<Self>,
+    ) {
+        let settings = matchmaking::room_settings(&first.tag);
+        let room_id = match self.add_room(first.session_id, MATCH_ROOM_NAME, settings, None, ctx) {
+            Ok(room_id) => room_id,
+            Err(error) => {
+                let _ = first.matched.send(Err(error.clone()));
+                let _ = second.matched.send(Err(error));
+                return;
+            }
+        };
+        for entry in [first, second] {
+            let matched = entry.matched;
+            ctx.spawn(self.enter_room(entry.session_id, room_id, None, false).map(
+                move |res, _, _| {
+                    let _ = matched.send(res);
+                },
+            ));
+        }
     }
 
+    fn cancel_matchmaking(&mut self, session_id: &Uuid) -> Result<QueueInfo, ServerError> {
+        match self.queue.leave(session_id) {
+            Some(entry) => Ok(self.queue.info(&entry.tag)),
+            None => Err(ServerError::new(
+                ErrorCode::NotQueued,
+                "not waiting for match",
+            )),
+        }
+    }
+
+    /// Remove session from its room
+    fn leave_room(
+        &mut self,
+        session_id: Uuid,
+    ) -> ResponseActFuture<Self, Result<RoomInfo, ServerError>> {
+        let room_id = self
+            .sessions
+            .get_mut(&session_id)
+            .and_then(|session| session.room.take());
+        let addr = match room_id.and_then(|room_id| self.room_link(&room_id)) {
+            Some(addr) => addr,
+            None => return Box::pin(fut::ready(Err(ServerError::not_in_room()))),
+        };
+        Box::pin(
+            addr.send(RemoveMember { session_id }).into_actor(self).map(
+                move |res, act, _| match res {
+                    Ok(info) => info.ok_or_else(ServerError::not_in_room),
+                    Err(_) => {
+                        if let Some(room_id) = room_id {
+                            act.drop_room(&room_id);
+                        }
+                        Err(ServerError::not_in_room())
+                    }
+                },
+            ),
+        )
+    }
+
+    /// Set display name of session, renaming it in its room
+    fn set_name(
+        &mut self,
+        session_id: Uuid,
+        name: &str,
+    ) -> ResponseActFuture<Self, Result<PlayerName, ServerError>> {
+        let name = match room::validate_name(name) {
+            Ok(name) => name,
+            Err(error) => return Box::pin(fut::ready(Err(error))),
+        };
+        match self.sessions.get_mut(&session_id) {
+            Some(session) => session.name = Some(name.clone()),
+            None => return Box::pin(fut::ready(Err(ServerError::internal()))),
+        }
+        let addr = match self.session_room(&session_id) {
+            Some(addr) => addr,
+            None => return Box::pin(fut::ready(Ok(PlayerName { name }))),
+        };
+        Box::pin(
+            addr.send(RenameMember {
+                session_id,
+                name: name.clone(),
+            })
+            .into_actor(self)
+            .map(move |res, _, _| {
+                Ok(PlayerName {
+                    name: res.ok().flatten().unwrap_or(name),
+                })
+            }),
+        )
+    }
+
+    /// Forget stopped room, its members are no longer in it
     fn remove_room(&mut self, room_id: &Uuid) {
-        self.rooms.remove(room_id);
+        if let Some(handle) = self.rooms.remove(room_id) {
+            self.invite_codes.remove(&handle.invite_code);
+            self.publish(None, BusBody::RoomUnlisted { room_id: *room_id });
+        }
+        self.remote_members.retain(|_, (_, room)| room != room_id);
+        self.clear_room(room_id);
     }
 
-    fn add_session(&mut self, address: Recipient<ChatMessage>) -> Uuid {
+    /// Forget room on another instance which is closed or no longer announced
+    fn remove_remote_room(&mut self, room_id: &Uuid) {
+        if self.remote_rooms.remove(room_id).is_some() {
+            self.clear_room(room_id);
+        }
+    }
+
+    /// Sessions in the room are no longer in it
+    fn clear_room(&mut self, room_id: &Uuid) {
+        let session_ids: Vec<Uuid> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.room == Some(*room_id))
+            .map(|(session_id, _)| *session_id)
+            .collect();
+        for session_id in &session_ids {
+            self.take_out_of_room(session_id, room_id);
+        }
+        self.update_lobby();
+    }
+
+    /// Take session out of room it did not ask to leave, telling the session
+    fn take_out_of_room(&mut self, session_id: &Uuid, room_id: &Uuid) {
+        if let Some(session) = self.sessions.get_mut(session_id) {
+            if session.room == Some(*room_id) {
+                session.room = None;
+                if let Some(left) = &session.left {
+                    let _ = left.do_send(RoomLeft { room_id: *room_id });
+                }
+            }
+        }
+    }
+
+    /// Remove room whose actor stopped without closing it, telling members
+    fn drop_room(&mut self, room_id: &Uuid) {
+        if !self.rooms.contains_key(room_id) {
+            return;
+        }
+        println!("Room {} stopped unexpectedly", room_id);
+        if let Some(saver) = &self.saver {
+            saver.do_send(DeleteRoom(*room_id));
+        }
+        let closed = WsResponse::RoomClosed(RoomClosed {
+            room_id: *room_id,
+            reason: "room stopped unexpectedly".to_string(),
+        });
+        for (session_id, session) in &self.sessions {
+            if session.room == Some(*room_id) {
+                self.send_to_session(session_id, &closed);
+            }
+        }
+        self.remove_room(room_id);
+    }
+
+    fn add_session(
+        &mut self,
+        address: Recipient<ChatMessage>,
+        left: Option<Recipient<RoomLeft>>,
+    ) -> SessionInfo {
         let session_id = Uuid::new_v4();
-        self.sessions
-            .insert(session_id, Session { address: address });
-        session_id
-    }
-
-    fn remove_session(&mut self, msg: &Disconnect) -> Vec<Uuid> {
-        let mut rooms: Vec<Uuid> = Vec::new();
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (id, room) in &mut self.rooms {
-                if room.remove_member(&msg.id) {
-                    if room.is_empty() {
-                        rooms.push(id.clone());
-                    }
+        let resume_token = Uuid::new_v4();
+        self.sessions.insert(
+            session_id,
+            Session {
+                address: Some(address),
+                left,
+                resume_token,
+                disconnected_at: None,
+                room: None,
+                name: None,
+                client_id: None,
+                lobby: None,
+            },
+        );
+        SessionInfo {
+            session_id,
+            resume_token,
+            room: None,
+        }
+    }
+
+    /// Keep disconnected session for resume, returns whether it is kept
+    fn suspend_session(&mut self, msg: &Disconnect) -> bool {
+        match self.sessions.get_mut(&msg.id) {
+            // ignore connections which are already taken over by resume
+            Some(session) if session.resume_token == msg.resume_token => {
+                session.left = None;
+                if session.address.take().is_some() {
+                    session.disconnected_at = Some(Instant::now());
                 }
             }
+            _ => return false,
+        }
+        // disconnected session can not be told about its match
+        self.queue.leave(&msg.id);
+        if let Some(addr) = self.session_room(&msg.id) {
+            addr.do_send(MemberAddress {
+                session_id: msg.id,
+                address: None,
+                resume_token: msg.resume_token,
+            });
+        }
+        true
+    }
+
+    /// Move address of new session `msg.session_id` to resumed session
+    fn resume_session(&mut self, msg: &Resume) -> Option<SessionInfo> {
+        let session_id = self
+            .sessions
+            .iter()
+            .find(|(_, session)| session.resume_token == msg.resume_token)
+            .map(|(session_id, _)| *session_id)?;
+        if session_id == msg.session_id {
+            // client resumed the session it is connected as
+            let session = self.sessions.get(&session_id)?;
+            return Some(SessionInfo {
+                session_id,
+                resume_token: session.resume_token,
+                room: None,
+            });
+        }
+        let new_session = self.sessions.get(&msg.session_id)?;
+        let (address, left) = (new_session.address.clone(), new_session.left.clone());
+        let client_id = new_session.client_id.clone();
+        // leave rooms and queue the new session entered before resuming
+        self.remove_session(&msg.session_id);
+        let resume_token = Uuid::new_v4();
+        let session = self.sessions.get_mut(&session_id)?;
+        session.address = address;
+        session.left = left;
+        if client_id.is_some() {
+            session.client_id = client_id;
         }
-        rooms
+        session.resume_token = resume_token;
+        session.disconnected_at = None;
+        Some(SessionInfo {
+            session_id,
+            resume_token,
+            room: None,
+        })
+    }
+
+    /// Whether disconnected session has not been resumed within grace period
+    fn is_expired(&self, session_id: &Uuid) -> bool {
+        match self
+            .sessions
+            .get(session_id)
+            .and_then(|session| session.disconnected_at)
+        {
+            Some(disconnected_at) => disconnected_at.elapsed() >= self.config.resume_grace,
+            None => false,
+        }
+    }
+
+    /// Remove sessions whose grace period has passed, rooms whose actor
+    /// is gone and rooms other instances stopped announcing.
+    /// Rooms close themselves when they are idle
+    fn sweep(&mut self) {
+        let expired_sessions: Vec<Uuid> = self
+            .sessions
+            .keys()
+            .filter(|session_id| self.is_expired(session_id))
+            .copied()
+            .collect();
+        for session_id in &expired_sessions {
+            self.remove_session(session_id);
+        }
+        let dead_rooms: Vec<Uuid> = self
+            .rooms
+            .iter()
+            .filter(|(_, handle)| !handle.addr.connected())
+            .map(|(room_id, _)| *room_id)
+            .collect();
+        for room_id in &dead_rooms {
+            self.drop_room(room_id);
+        }
+        // instance which stopped announcing is gone with its rooms
+        let stale_rooms: Vec<Uuid> = self
+            .remote_rooms
+            .iter()
+            .filter(|(_, handle)| handle.last_seen.elapsed() >= self.config.sweep_interval * 3)
+            .map(|(room_id, _)| *room_id)
+            .collect();
+        for room_id in &stale_rooms {
+            self.remove_remote_room(room_id);
+        }
+        for room_id in self.rooms.keys() {
+            self.publish_room(room_id);
+        }
+    }
+
+    /// Remember room another instance announced
+    fn list_remote_room(
+        &mut self,
+        host: String,
+        info: RoomInfo,
+        invite_code: String,
+        password_salt: Option<String>,
+    ) {
+        if self.rooms.contains_key(&info.id) {
+            return;
+        }
+        match self.remote_rooms.get_mut(&info.id) {
+            Some(handle) => {
+                handle.info = info;
+                handle.invite_code = invite_code;
+                handle.password_salt = password_salt;
+                handle.last_seen = Instant::now();
+            }
+            None => {
+                let addr = RemoteRoom::new(
+                    info.id,
+                    self.config.instance_id.clone(),
+                    host,
+                    self.bus.clone(),
+                )
+                .start();
+                self.remote_rooms.insert(
+                    info.id,
+                    RemoteRoomHandle {
+                        addr,
+                        info,
+                        invite_code,
+                        password_salt,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+        self.update_lobby();
+    }
+
+    /// Pass request from another instance to the room, and its result back
+    fn handle_request(
+        &mut self,
+        from: String,
+        request_id: Uuid,
+        room_id: Uuid,
+        request: RoomRequest,
+        ctx: &mut Context<Self>,
+    ) {
+        let addr = match self.rooms.get(&room_id) {
+            Some(handle) => handle.addr.clone(),
+            None => {
+                let body = BusBody::Reply {
+                    request_id,
+                    room_id,
+                    result: None,
+                };
+                return self.publish(Some(from), body);
+            }
+        };
+        let reply = Reply {
+            to: from.clone(),
+            request_id,
+            room_id,
+        };
+        match request {
+            RoomRequest::AddMember {
+                session_id,
+                name,
+                client_id,
+                connected,
+                resume_token,
+                password_hash,
+                spectate,
+            } => {
+                self.remote_members
+                    .insert(session_id, (from.clone(), room_id));
+                let msg = AddMember {
+                    session_id,
+                    name,
+                    client_id,
+                    address: self.remote_address(session_id, &from, connected),
+                    resume_token,
+                    password: password_hash.map(JoinPassword::Hashed),
+                    spectate,
+                };
+                self.forward(addr, msg, reply, ctx);
+            }
+            RoomRequest::MemberAddress {
+                session_id,
+                connected,
+                resume_token,
+            } => {
+                let msg = MemberAddress {
+                    session_id,
+                    address: self.remote_address(session_id, &from, connected),
+                    resume_token,
+                };
+                self.forward(addr, msg, reply, ctx);
+            }
+            RoomRequest::RemoveMember(msg) => {
+                self.remote_members.remove(&msg.session_id);
+                self.forward(addr, msg, reply, ctx);
+            }
+            RoomRequest::RenameMember(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::Message(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::MoveCards(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::PlayDeck(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::Replay(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::SendChat(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::Kick(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::SetReady(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::FinishGame(msg) => self.forward(addr, msg, reply, ctx),
+            RoomRequest::Lock(msg) => self.forward(addr, msg, reply, ctx),
+        }
+    }
+
+    /// Send message to the room, publishing its result as `reply`
+    fn forward<M>(&self, addr: Addr<RoomActor>, msg: M, reply: Reply, ctx: &mut Context<Self>)
+    where
+        M: actix::Message + Send + 'static,
+        M::Result: Serialize + Send,
+        RoomActor: Handler<M>,
+    {
+        ctx.spawn(addr.send(msg).into_actor(self).map(move |res, act, _| {
+            let body = BusBody::Reply {
+                request_id: reply.request_id,
+                room_id: reply.room_id,
+                result: res
+                    .ok()
+                    .and_then(|result| serde_json::to_value(result).ok()),
+            };
+            act.publish(Some(reply.to), body);
+        }));
+    }
+
+    /// Address delivering room messages to session on `host` instance
+    fn remote_address(
+        &self,
+        session_id: Uuid,
+        host: &str,
+        connected: bool,
+    ) -> Option<Recipient<ChatMessage>> {
+        if !connected {
+            return None;
+        }
+        let session = RemoteSession::new(
+            session_id,
+            self.config.instance_id.clone(),
+            host.to_string(),
+            self.bus.clone(),
+        );
+        Some(session.start().recipient())
+    }
+
+    fn remove_session(&mut self, session_id: &Uuid) {
+        // another member becomes host if it was room host
+        if let Some(addr) = self.session_room(session_id) {
+            addr.do_send(RemoveMember {
+                session_id: *session_id,
+            });
+        }
+        self.queue.leave(session_id);
+        self.sessions.remove(session_id);
     }
 }
 
+/// Where result of request from another instance goes
+struct Reply {
+    to: String,
+    request_id: Uuid,
+    room_id: Uuid,
+}
+
 /// Make actor from `ChatServer`
 impl Actor for ChatServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.arbiters = (0..self.config.room_threads)
+            .map(|_| Arbiter::new())
+            .collect();
+        self.saver = self.store.clone().map(RoomSaver::start);
+        if let Err(error) = self.bus.subscribe(ctx.address().recipient()) {
+            println!("Failed to subscribe to message bus: {}", error);
+        }
+        self.restore_rooms(ctx);
+        ctx.run_interval(self.config.sweep_interval, |act, _| act.sweep());
+    }
 }
 
 /// Handler for Connect message.
@@ -113,7 +1051,7 @@ impl Handler<Connect> for ChatServer {
         println!("Someone joined");
 
         // register session with random id
-        MessageResult(self.add_session(msg.addr))
+        MessageResult(self.add_session(msg.addr, msg.left))
     }
 }
 
@@ -121,25 +1059,60 @@ impl Handler<Connect> for ChatServer {
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
         println!("Someone disconnected");
 
-        // remove address
-        // if a room host is disconnected, non-host member should close websocket
-        let room_ids = self.remove_session(&msg);
-        for room_id in room_ids {
-            self.remove_room(&room_id);
+        // keep rooms until grace period passes so that client can resume
+        if !self.suspend_session(&msg) {
+            return;
         }
-        self.update_room_list();
+        let session_id = msg.id;
+        ctx.run_later(self.config.resume_grace, move |act, _| {
+            if act.is_expired(&session_id) {
+                act.remove_session(&session_id);
+            }
+        });
     }
 }
 
-/// Handler for Message message.
-impl Handler<Message> for ChatServer {
-    type Result = ();
+/// Handler for Resume message.
+impl Handler<Resume> for ChatServer {
+    type Result = ResponseActFuture<Self, Option<ResumedSession>>;
 
-    fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
+    fn handle(&mut self, msg: Resume, _: &mut Context<Self>) -> Self::Result {
+        println!("Someone resumed");
+
+        let info = match self.resume_session(&msg) {
+            Some(info) => info,
+            None => return Box::pin(fut::ready(None)),
+        };
+        let addr = match self.session_room(&info.session_id) {
+            Some(addr) => addr,
+            None => return Box::pin(fut::ready(Some(ResumedSession { info, room: None }))),
+        };
+        let address = self
+            .sessions
+            .get(&info.session_id)
+            .and_then(|session| session.address.clone());
+        Box::pin(
+            addr.send(MemberAddress {
+                session_id: info.session_id,
+                address,
+                resume_token: info.resume_token,
+            })
+            .into_actor(self)
+            .map(move |res, _, _| match res {
+                Ok(Some(room)) => Some(ResumedSession {
+                    info: SessionInfo {
+                        room: Some(room),
+                        ..info
+                    },
+                    room: Some(addr),
+                }),
+                // room is gone while suspended
+                _ => Some(ResumedSession { info, room: None }),
+            }),
+        )
     }
 }
 
@@ -148,62 +1121,425 @@ impl Handler<ListRooms> for ChatServer {
     type Result = MessageResult<ListRooms>;
 
     fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
-        let mut rooms = Vec::new();
+        MessageResult(self.room_list())
+    }
+}
 
-        for (room_id, Room { name, members }) in &self.rooms {
-            let room = RoomInfo {
-                id: room_id.clone(),
-                name: name.to_owned(),
-                num: members.len(),
-            };
-            rooms.push(room);
-        }
+/// Handler for Subscribe message.
+impl Handler<Subscribe> for ChatServer {
+    type Result = MessageResult<Subscribe>;
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.subscribe(&msg.session_id, msg.query))
+    }
+}
+
+/// Handler for Unsubscribe message.
+impl Handler<Unsubscribe> for ChatServer {
+    type Result = MessageResult<Unsubscribe>;
 
-        MessageResult(RoomInfoList { rooms })
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.unsubscribe(&msg.session_id))
     }
 }
 
-/// Join room, send disconnect message to old room
-/// send join message to new room
+/// Handler for Matchmake message.
+impl Handler<Matchmake> for ChatServer {
+    type Result = MessageResult<Matchmake>;
+
+    fn handle(&mut self, msg: Matchmake, ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.matchmake(msg.session_id, msg.tag, ctx))
+    }
+}
+
+/// Handler for CancelMatchmaking message.
+impl Handler<CancelMatchmaking> for ChatServer {
+    type Result = MessageResult<CancelMatchmaking>;
+
+    fn handle(&mut self, msg: CancelMatchmaking, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.cancel_matchmaking(&msg.session_id))
+    }
+}
+
+/// Join room, leaving old room after new room accepts
 impl Handler<Join> for ChatServer {
-    type Result = MessageResult<Join>;
+    type Result = ResponseActFuture<Self, Result<JoinedRoom, ServerError>>;
 
     fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> Self::Result {
         let Join {
             session_id,
             room_id,
+            password,
+            spectate,
         } = msg;
 
-        // send all users in the room except self
-        let msg = SimpleMessage {
-            message: "Someone is connected".to_string(),
+        match self.find_room(&session_id, room_id) {
+            Ok(room_id) => self.enter_room(session_id, room_id, password, spectate),
+            Err(error) => Box::pin(fut::ready(Err(error))),
         }
-        .get_json_data(Status::Ok, Event::SomeoneEnterRoom);
-        self.send_message(&room_id, &msg, session_id);
-        // add session id
-        self.rooms
-            .get_mut(&room_id)
-            // TODO Result
-            .unwrap()
-            .members
-            .insert(session_id);
-        MessageResult(RoomInfo {
-            id: room_id,
-            name: self.rooms.get(&room_id).unwrap().name.clone(),
-            num: self.rooms.get(&room_id).unwrap().members.len(),
-        })
+    }
+}
+
+/// Handler for JoinByCode message.
+impl Handler<JoinByCode> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<JoinedRoom, ServerError>>;
+
+    fn handle(&mut self, msg: JoinByCode, _: &mut Context<Self>) -> Self::Result {
+        let JoinByCode {
+            session_id,
+            invite_code,
+            password,
+            spectate,
+        } = msg;
+
+        match self.find_room_by_code(&invite_code) {
+            Ok(room_id) => self.enter_room(session_id, room_id, password, spectate),
+            Err(error) => Box::pin(fut::ready(Err(error))),
+        }
+    }
+}
+
+/// Handler for Leave message.
+impl Handler<Leave> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<RoomInfo, ServerError>>;
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) -> Self::Result {
+        self.leave_room(msg.session_id)
+    }
+}
+
+/// Handler for SendChat message, passed to the session's room
+impl Handler<SendChat> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<ChatEntry, ServerError>>;
+
+    fn handle(&mut self, msg: SendChat, _: &mut Context<Self>) -> Self::Result {
+        match self.session_room(&msg.session_id) {
+            Some(addr) => Box::pin(
+                addr.send(msg)
+                    .into_actor(self)
+                    .map(|res, _, _| res.unwrap_or_else(|_| Err(ServerError::internal()))),
+            ),
+            None => Box::pin(fut::ready(Err(ServerError::not_in_room()))),
+        }
+    }
+}
+
+/// Handler for SetClientId message.
+impl Handler<SetClientId> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetClientId, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.session_id) {
+            session.client_id = Some(msg.client_id);
+        }
+    }
+}
+
+/// Handler for SetName message.
+impl Handler<SetName> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<PlayerName, ServerError>>;
+
+    fn handle(&mut self, msg: SetName, _: &mut Context<Self>) -> Self::Result {
+        self.set_name(msg.session_id, &msg.name)
     }
 }
 
 impl Handler<Create> for ChatServer {
-    type Result = MessageResult<Create>;
+    type Result = ResponseActFuture<Self, Result<JoinedRoom, ServerError>>;
 
-    fn handle(&mut self, msg: Create, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Create, ctx: &mut Context<Self>) -> Self::Result {
         let Create {
             session_id,
             room_name,
+            settings,
+            password,
         } = msg;
 
-        self.add_room(&session_id, &room_name)
+        match self.add_room(session_id, &room_name, settings, password.clone(), ctx) {
+            Ok(room_id) => self.enter_room(session_id, room_id, password, false),
+            Err(error) => Box::pin(fut::ready(Err(error))),
+        }
+    }
+}
+
+/// Handler for RoomChanged message.
+impl Handler<RoomChanged> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomChanged, _: &mut Context<Self>) {
+        if let Some(handle) = self.rooms.get_mut(&msg.room_id) {
+            handle.info = msg.info;
+            self.publish_room(&msg.room_id);
+            self.update_lobby();
+        }
+    }
+}
+
+/// Handler for MemberRemoved message.
+impl Handler<MemberRemoved> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MemberRemoved, _: &mut Context<Self>) {
+        self.take_out_of_room(&msg.session_id, &msg.room_id);
+        // member is connected to another instance
+        if let Some((instance, _)) = self.remote_members.remove(&msg.session_id) {
+            self.publish(
+                Some(instance),
+                BusBody::MemberRemoved {
+                    room_id: msg.room_id,
+                    session_id: msg.session_id,
+                },
+            );
+        }
+    }
+}
+
+/// Handler for RoomStopped message.
+impl Handler<RoomStopped> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomStopped, _: &mut Context<Self>) {
+        self.remove_room(&msg.room_id);
+    }
+}
+
+/// Handler for BusMessage message.
+impl Handler<BusMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: BusMessage, ctx: &mut Context<Self>) {
+        let instance = &self.config.instance_id;
+        if msg.from == *instance || matches!(&msg.to, Some(to) if to != instance) {
+            return;
+        }
+        match msg.body {
+            BusBody::RoomListed {
+                info,
+                invite_code,
+                password_salt,
+            } => self.list_remote_room(msg.from, info, invite_code, password_salt),
+            BusBody::RoomUnlisted { room_id } => self.remove_remote_room(&room_id),
+            BusBody::Request {
+                request_id,
+                room_id,
+                request,
+            } => self.handle_request(msg.from, request_id, room_id, request, ctx),
+            BusBody::Reply {
+                request_id,
+                room_id,
+                result,
+            } => {
+                if let Some(handle) = self.remote_rooms.get(&room_id) {
+                    handle.addr.do_send(ReplyArrived { request_id, result });
+                }
+            }
+            BusBody::Deliver {
+                session_id,
+                message,
+            } => {
+                if let Some(Session {
+                    address: Some(address),
+                    ..
+                }) = self.sessions.get(&session_id)
+                {
+                    let _ = address.do_send(ChatMessage(message));
+                }
+            }
+            BusBody::MemberRemoved {
+                room_id,
+                session_id,
+            } => self.take_out_of_room(&session_id, &room_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// Client end of session, gets what chat server and rooms push
+    struct Client(mpsc::UnboundedSender<String>);
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ChatMessage> for Client {
+        type Result = ();
+
+        fn handle(&mut self, msg: ChatMessage, _: &mut Context<Self>) {
+            let _ = self.0.unbounded_send(msg.0);
+        }
+    }
+
+    async fn connect(server: &Addr<ChatServer>) -> (SessionInfo, mpsc::UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let addr = Client(sender).start().recipient();
+        let info = server.send(Connect { addr, left: None }).await.unwrap();
+        (info, receiver)
+    }
+
+    /// Next pushed message of `event`, skipping others
+    async fn next_event(receiver: &mut mpsc::UnboundedReceiver<String>, event: &str) -> String {
+        loop {
+            let message = receiver.next().await.unwrap();
+            if message.contains(&format!("\"event\":\"{}\"", event)) {
+                return message;
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_session_sends_cards_without_ready_check() {
+        System::new("test").block_on(async {
+            let server = ChatServer::new(ChatServerConfig::default()).start();
+            let (host, _host_messages) = connect(&server).await;
+            let (guest, mut guest_messages) = connect(&server).await;
+
+            let joined = match WsRequest::from_legacy("/create room") {
+                Ok(WsRequest::Create {
+                    name,
+                    settings,
+                    password,
+                }) => server
+                    .send(Create {
+                        session_id: host.session_id,
+                        room_name: name,
+                        settings,
+                        password,
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                _ => panic!("/create is not parsed"),
+            };
+            match WsRequest::from_legacy(&format!("/join {}", joined.info.id)) {
+                Ok(WsRequest::Join {
+                    room_id,
+                    password,
+                    spectate,
+                }) => server
+                    .send(Join {
+                        session_id: guest.session_id,
+                        room_id,
+                        password,
+                        spectate,
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                _ => panic!("/join is not parsed"),
+            };
+            let cards = r#"/cards [{"id": 1, "face": "face.png", "back": "back.png",
+                "index": 0, "own": true, "position": {"x": 1.0, "y": 2.0}}]"#;
+            let cards = match WsRequest::from_legacy(cards) {
+                Ok(WsRequest::Cards { cards }) => cards,
+                _ => panic!("/cards is not parsed"),
+            };
+            let message = Message {
+                id: host.session_id,
+                first: false,
+                cards: CardInfoList { cards },
+                legacy: !protocol::supports(1, "room-state"),
+            };
+            assert_eq!(joined.info.state, RoomState::Lobby);
+            assert!(joined.addr.send(message).await.unwrap().is_ok());
+            let told = next_event(&mut guest_messages, "CardsInfo").await;
+            assert!(told.contains("face.png"));
+        });
+    }
+
+    async fn create(server: &Addr<ChatServer>, session_id: Uuid) -> JoinedRoom {
+        server
+            .send(Create {
+                session_id,
+                room_name: "room".to_string(),
+                settings: RoomSettings::default(),
+                password: None,
+            })
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn join(
+        server: &Addr<ChatServer>,
+        session_id: Uuid,
+        room_id: Uuid,
+    ) -> Result<JoinedRoom, ServerError> {
+        server
+            .send(Join {
+                session_id,
+                room_id,
+                password: None,
+                spectate: false,
+            })
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn banned_client_is_refused_after_reconnecting() {
+        System::new("test").block_on(async {
+            let server = ChatServer::new(ChatServerConfig::default()).start();
+            let (host, _host_messages) = connect(&server).await;
+            let joined = create(&server, host.session_id).await;
+            let room_id = joined.info.id;
+
+            let (guest, _guest_messages) = connect(&server).await;
+            server.do_send(SetClientId {
+                session_id: guest.session_id,
+                client_id: "device-1".to_string(),
+            });
+            server
+                .send(SetName {
+                    session_id: guest.session_id,
+                    name: "Mallory".to_string(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(join(&server, guest.session_id, room_id).await.is_ok());
+            let kick = Kick {
+                session_id: host.session_id,
+                target: guest.session_id,
+                ban: true,
+            };
+            assert!(joined.addr.send(kick).await.unwrap().is_ok());
+
+            // same client from a new connection
+            let (again, _again_messages) = connect(&server).await;
+            server.do_send(SetClientId {
+                session_id: again.session_id,
+                client_id: "device-1".to_string(),
+            });
+            let error = join(&server, again.session_id, room_id)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.code, ErrorCode::Banned);
+
+            // same name without client id
+            let (renamed, _renamed_messages) = connect(&server).await;
+            server
+                .send(SetName {
+                    session_id: renamed.session_id,
+                    name: "mallory".to_string(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let error = join(&server, renamed.session_id, room_id)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.code, ErrorCode::Banned);
+
+            let (other, _other_messages) = connect(&server).await;
+            assert!(join(&server, other.session_id, room_id).await.is_ok());
+        });
     }
 }
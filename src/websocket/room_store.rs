@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use actix::prelude::*;
+use uuid::Uuid;
+
+/// Room saved to restore it after restart
+pub struct SavedRoom {
+    pub id: Uuid,
+    /// instance hosting the room
+    pub instance: String,
+    pub name: String,
+    /// session ids of members
+    pub players: Vec<Uuid>,
+    /// room with resume tokens of members in JSON
+    pub checkpoint: String,
+}
+
+/// Where rooms are saved, so that games survive server restart
+pub trait RoomStore: Send + Sync {
+    /// Insert room or overwrite the saved one
+    fn save(&self, room: &SavedRoom) -> Result<(), Box<dyn Error>>;
+    fn delete(&self, room_id: &Uuid) -> Result<(), Box<dyn Error>>;
+    /// Rooms saved by `instance`
+    fn load(&self, instance: &str) -> Result<Vec<SavedRoom>, Box<dyn Error>>;
+}
+
+/// Saves and deletes rooms on its own thread, one at a time in the order
+/// asked, so that rooms on the same arbiter are not held up by the store
+pub struct RoomSaver {
+    store: Arc<dyn RoomStore>,
+}
+
+impl RoomSaver {
+    pub fn start(store: Arc<dyn RoomStore>) -> Addr<RoomSaver> {
+        // a single thread keeps later saves of a room from being overwritten by earlier ones
+        SyncArbiter::start(1, move || RoomSaver {
+            store: store.clone(),
+        })
+    }
+}
+
+impl Actor for RoomSaver {
+    type Context = SyncContext<Self>;
+}
+
+/// Insert room or overwrite the saved one, sent to `RoomSaver`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SaveRoom(pub SavedRoom);
+
+/// Delete saved room, sent to `RoomSaver`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeleteRoom(pub Uuid);
+
+/// Handler for SaveRoom message.
+impl Handler<SaveRoom> for RoomSaver {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveRoom, _: &mut SyncContext<Self>) {
+        if let Err(error) = self.store.save(&msg.0) {
+            println!("Failed to save room {}: {}", msg.0.id, error);
+        }
+    }
+}
+
+/// Handler for DeleteRoom message.
+impl Handler<DeleteRoom> for RoomSaver {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeleteRoom, _: &mut SyncContext<Self>) {
+        if let Err(error) = self.store.delete(&msg.0) {
+            println!("Failed to delete room {}: {}", msg.0, error);
+        }
+    }
+}
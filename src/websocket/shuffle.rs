@@ -0,0 +1,124 @@
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Secret seed of one game, every shuffle in the game is derived from it.
+/// Players are told its hash when game starts and the seed when it ends,
+/// so that they can check shuffles were not changed after the start
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Seed([u8; 32]);
+
+impl Seed {
+    pub fn generate() -> Seed {
+        Seed(rand::thread_rng().gen())
+    }
+
+    /// SHA-256 of seed in hex
+    pub fn commitment(&self) -> String {
+        to_hex(&Sha256::digest(&self.0))
+    }
+
+    /// Seed in hex
+    pub fn reveal(&self) -> String {
+        to_hex(&self.0)
+    }
+
+    /// Random numbers to shuffle deck of `player`: ChaCha20 keystream keyed
+    /// with SHA-256 of seed followed by bytes of player's session id, with
+    /// zero nonce from block 0, read as little endian u64s.
+    /// Players get their own one so that order of dealing does not matter
+    pub fn rng_for(&self, player: &Uuid) -> ChaCha20Rng {
+        let mut hasher = Sha256::new();
+        hasher.update(self.0);
+        hasher.update(player.as_bytes());
+        let mut key = [0; 32];
+        key.copy_from_slice(&hasher.finalize());
+        ChaCha20Rng::from_seed(key)
+    }
+}
+
+/// Fisher-Yates shuffle clients can repeat to check a revealed seed.
+/// For `i` from `len - 1` down to 1, item `i` is swapped with item
+/// `x % (i + 1)`, where `x` is the first u64 of `rng` below
+/// `u64::MAX - u64::MAX % (i + 1)`; larger ones are skipped to avoid bias
+pub fn shuffle<T, R: RngCore>(items: &mut [T], rng: &mut R) {
+    for i in (1..items.len()).rev() {
+        let bound = i as u64 + 1;
+        let limit = u64::MAX - u64::MAX % bound;
+        let x = loop {
+            let x = rng.next_u64();
+            if x < limit {
+                break x;
+            }
+        };
+        items.swap(i, (x % bound) as usize);
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::board::Board;
+    use super::super::deck_store::DeckCard;
+    use super::*;
+
+    fn deck() -> Vec<DeckCard> {
+        [(2, 3), (1, 4), (3, 2)]
+            .iter()
+            .map(|&(card_id, num)| DeckCard {
+                card_id,
+                face: format!("{}.png", card_id),
+                back: "back.png".to_string(),
+                num,
+            })
+            .collect()
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn shuffle_matches_documented_algorithm() {
+        // same as other ChaCha20 implementations give for zero key and nonce
+        let mut items: Vec<u32> = (0..10).collect();
+        shuffle(&mut items, &mut ChaCha20Rng::from_seed([0; 32]));
+        assert_eq!(items, [9, 7, 3, 6, 1, 4, 8, 5, 2, 0]);
+    }
+
+    #[test]
+    fn revealed_seed_reproduces_dealt_order() {
+        let seed = Seed::generate();
+        let player = Uuid::new_v4();
+        let mut board = Board::default();
+        let dealt: Vec<i32> = board
+            .deal_deck(player, 1, &deck(), &mut seed.rng_for(&player))
+            .iter()
+            .map(|card| card.card_id)
+            .collect();
+
+        // what a client does with the seed revealed at game end
+        let revealed = from_hex(&seed.reveal());
+        assert_eq!(to_hex(&Sha256::digest(&revealed)), seed.commitment());
+        let mut hasher = Sha256::new();
+        hasher.update(&revealed);
+        hasher.update(player.as_bytes());
+        let mut key = [0; 32];
+        key.copy_from_slice(&hasher.finalize());
+        let mut copies: Vec<i32> = deck()
+            .iter()
+            .flat_map(|card| (0..card.num).map(move |_| card.card_id))
+            .collect();
+        copies.sort_unstable();
+        shuffle(&mut copies, &mut ChaCha20Rng::from_seed(key));
+        assert_eq!(copies, dealt);
+    }
+}
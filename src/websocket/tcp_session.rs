@@ -7,20 +7,19 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio_util::codec::FramedRead;
 use uuid::Uuid;
 
-use super::Message;
 use super::*;
 
 /// `ChatSession` actor is responsible for tcp peer communications.
 pub struct ChatSession {
     /// unique session id
     id: Uuid,
+    /// token to resume this session
+    resume_token: Uuid,
     /// this is address of chat server
     addr: Addr<room_manager::ChatServer>,
     /// Client must send ping at least once per 10 seconds, otherwise we drop
     /// connection.
     hb: Instant,
-    /// joined room
-    room: Option<Uuid>,
     /// Framed wrapper
     framed: actix::io::FramedWrite<codec::ChatResponse, WriteHalf<TcpStream>, codec::ChatCodec>,
 }
@@ -41,11 +40,15 @@ impl Actor for ChatSession {
         self.addr
             .send(Connect {
                 addr: addr.recipient(),
+                left: None,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(res) => {
+                        act.id = res.session_id;
+                        act.resume_token = res.resume_token;
+                    }
                     // something is wrong with chat server
                     _ => ctx.stop(),
                 }
@@ -56,7 +59,10 @@ impl Actor for ChatSession {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // notify chat server
-        self.addr.do_send(Disconnect { id: self.id });
+        self.addr.do_send(Disconnect {
+            id: self.id,
+            resume_token: self.resume_token,
+        });
         Running::Stop
     }
 }
@@ -89,27 +95,60 @@ impl StreamHandler<Result<codec::ChatRequest, io::Error>> for ChatSession {
             }
             Ok(codec::ChatRequest::Join(roomid)) => {
                 println!("Join to room id: {}", roomid);
-                self.room = Some(roomid);
-                self.addr.do_send(Join {
-                    session_id: self.id,
-                    room_id: roomid,
-                });
-                self.framed.write(codec::ChatResponse::Joined(roomid));
+                self.addr
+                    .send(Join {
+                        session_id: self.id,
+                        room_id: roomid,
+                        password: None,
+                        spectate: false,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Ok(_)) => {
+                                act.framed.write(codec::ChatResponse::Joined(roomid));
+                            }
+                            Ok(Err(error)) => act.framed.write(codec::ChatResponse::Error(error)),
+                            _ => act
+                                .framed
+                                .write(codec::ChatResponse::Error(ServerError::internal())),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx)
             }
             Ok(codec::ChatRequest::Message(message)) => {
                 // send message to chat server
                 println!("Peer message: {}", message);
-                if let Some(room) = self.room {
-                    self.addr.do_send(Message {
-                        id: self.id,
-                        msg: message,
-                        room: room,
+                self.addr
+                    .send(SendChat {
+                        session_id: self.id,
+                        text: message,
                     })
-                }
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(_)) => (),
+                            Ok(Err(error)) => act.framed.write(codec::ChatResponse::Error(error)),
+                            _ => act
+                                .framed
+                                .write(codec::ChatResponse::Error(ServerError::internal())),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx)
             }
             // we update heartbeat time on ping from peer
             Ok(codec::ChatRequest::Ping) => self.hb = Instant::now(),
-            _ => ctx.stop(),
+            Err(error) => {
+                // frame can not be decoded, stream is out of sync
+                self.framed
+                    .write(codec::ChatResponse::Error(ServerError::new(
+                        ErrorCode::MalformedPayload,
+                        &error.to_string(),
+                    )));
+                ctx.stop()
+            }
         }
     }
 }
@@ -133,10 +172,9 @@ impl ChatSession {
     ) -> ChatSession {
         ChatSession {
             id: Uuid::new_v4(),
+            resume_token: Uuid::nil(),
             addr,
             hb: Instant::now(),
-            // defaultルームへの割り当てなし
-            room: None,
             framed,
         }
     }
@@ -152,7 +190,10 @@ impl ChatSession {
                 println!("Client heartbeat failed, disconnecting!");
 
                 // notify chat server
-                act.addr.do_send(Disconnect { id: act.id });
+                act.addr.do_send(Disconnect {
+                    id: act.id,
+                    resume_token: act.resume_token,
+                });
 
                 // stop actor
                 ctx.stop();
@@ -2,25 +2,32 @@ use std::time::Instant;
 
 use actix::Addr;
 use actix_web_actors::ws;
+use futures::channel::oneshot;
 use uuid::Uuid;
 
+use super::remote_room::RoomLink;
 use super::Message;
 use super::*;
 
 /// `WsChatSession` is Actor for websocket
 pub struct WsChatSession {
-    // TODO newの実装を行い、pubをつけないように変更
     /// unique session id
-    pub id: Uuid,
+    id: Uuid,
+    /// token to take over this session after reconnecting
+    resume_token: Uuid,
     /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
     /// otherwise we drop connection.
-    pub hb: Instant,
-    /// joined room
-    pub room: Option<Uuid>,
-    /// peer name
-    pub name: Option<String>,
+    hb: Instant,
+    /// joined room, room requests go straight to it
+    room: Option<RoomLink>,
+    /// id of joined room
+    room_id: Option<Uuid>,
+    /// negotiated protocol version
+    version: u32,
+    /// whether client declared its version on connect
+    announce_version: bool,
     /// Chat server
-    pub addr: Addr<room_manager::ChatServer>,
+    addr: Addr<room_manager::ChatServer>,
 }
 
 impl Actor for WsChatSession {
@@ -40,12 +47,20 @@ impl Actor for WsChatSession {
         let addr = ctx.address();
         self.addr
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                left: Some(addr.recipient()),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(res) => {
+                        act.id = res.session_id;
+                        act.resume_token = res.resume_token;
+                        // answer version declared in query parameter
+                        if act.announce_version {
+                            act.send_hello(&None, ctx);
+                        }
+                    }
                     // something is wrong with chat server
                     _ => ctx.stop(),
                 }
@@ -56,23 +71,41 @@ impl Actor for WsChatSession {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // notify chat server
-        self.addr.do_send(Disconnect { id: self.id });
+        self.addr.do_send(Disconnect {
+            id: self.id,
+            resume_token: self.resume_token,
+        });
         Running::Stop
     }
 }
 
 /// Handle messages from chat server, we simply send it to peer websocket
+/// unless client is too old for it
 impl Handler<ChatMessage> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: ChatMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        if let Some(message) = protocol::for_version(self.version, msg.0) {
+            ctx.text(message);
+        }
+    }
+}
+
+/// Handler for RoomLeft message.
+impl Handler<RoomLeft> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomLeft, _: &mut Self::Context) {
+        // session may have joined another room since
+        if self.room_id == Some(msg.room_id) {
+            self.room = None;
+            self.room_id = None;
+        }
     }
 }
 
 /// WebSocket message handler
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
-    // TODO: "/command"形式ではなく、json形式に揃える
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
             Err(error) => {
@@ -94,164 +127,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             }
             ws::Message::Text(text) => {
                 let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            self.addr
-                                .send(ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            ctx.text(
-                                                rooms.get_json_data(Status::Ok, Event::GetRoomList),
-                                            );
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = Some(Uuid::parse_str(v[1]).unwrap());
-                                self.addr
-                                    .send(Join {
-                                        session_id: self.id,
-                                        room_id: Uuid::parse_str(v[1]).unwrap(),
-                                    })
-                                    .into_actor(self)
-                                    .then(|res, _, ctx| {
-                                        match res {
-                                            Ok(room_info) => {
-                                                ctx.text(
-                                                    room_info.get_json_data(
-                                                        Status::Ok,
-                                                        Event::EnterRoom,
-                                                    ),
-                                                );
-                                            }
-                                            _ => println!("Something is wrong!"),
-                                        }
-                                        fut::ready(())
-                                    })
-                                    .wait(ctx)
-                            } else {
-                                ctx.text(
-                                    SimpleMessage {
-                                        message: "!!! room id is required".to_string(),
-                                    }
-                                    .get_json_data(Status::Error, Event::EnterRoom),
-                                );
-                            }
-                        }
-                        "/create" => {
-                            if v.len() == 2 {
-                                self.addr
-                                    .send(Create {
-                                        session_id: self.id,
-                                        room_name: v[1].to_owned(),
-                                    })
-                                    .into_actor(self)
-                                    .then(|res, _, ctx| {
-                                        match res {
-                                            Ok(createroom) => {
-                                                let data = RoomInfo {
-                                                    id: createroom.room_id,
-                                                    name: createroom.room_name,
-                                                    num: 0,
-                                                };
-                                                ctx.text(
-                                                    data.get_json_data(
-                                                        Status::Ok,
-                                                        Event::CreateRoom,
-                                                    ),
-                                                );
-                                            }
-                                            // TODO: statusをerrorとして返した方がよい？
-                                            _ => println!("Something is wrong"),
-                                        }
-                                        fut::ready(())
-                                    })
-                                    .wait(ctx)
-                            } else {
-                                ctx.text(
-                                    SimpleMessage {
-                                        message: "!!! room name is required".to_string(),
-                                    }
-                                    .get_json_data(Status::Error, Event::Unknown),
-                                );
-                            }
-                        }
-                        "/first-cards" => {
-                            if v.len() == 2 {
-                                let msg = v[1].to_owned();
-                                dbg!(&msg);
-                                let card_info: Vec<CardInfo> = serde_json::from_str(&msg).unwrap();
-                                let card_info_list = CardInfoList { cards: card_info };
-                                if let Some(room) = self.room {
-                                    self.addr.do_send(Message {
-                                        id: self.id,
-                                        msg: card_info_list
-                                            .get_json_data(Status::Ok, Event::FirstCardsInfo),
-                                        room,
-                                    })
-                                }
-                            } else {
-                                ctx.text(
-                                    SimpleMessage {
-                                        message: "!!! cards info is required".to_string(),
-                                    }
-                                    .get_json_data(Status::Error, Event::Unknown),
-                                );
-                            }
-                        }
-                        "/cards" => {
-                            if v.len() == 2 {
-                                let msg = v[1].to_owned();
-                                dbg!(&msg);
-                                let card_info: Vec<CardInfo> = serde_json::from_str(&msg).unwrap();
-                                let card_info_list = CardInfoList { cards: card_info };
-                                if let Some(room) = self.room {
-                                    self.addr.do_send(Message {
-                                        id: self.id,
-                                        msg: card_info_list
-                                            .get_json_data(Status::Ok, Event::CardsInfo),
-                                        room,
-                                    })
-                                }
-                            } else {
-                                ctx.text(
-                                    SimpleMessage {
-                                        message: "!!! cards info is required".to_string(),
-                                    }
-                                    .get_json_data(Status::Error, Event::Unknown),
-                                );
-                            }
-                        }
-                        _ => ctx.text(
-                            SimpleMessage {
-                                message: format!("!!! unknown command: {:?}", m),
-                            }
-                            .get_json_data(Status::Error, Event::Unknown),
-                        ),
-                    }
+                let (request_id, request) = if m.starts_with('/') {
+                    // old "/command" style message
+                    (None, WsRequest::from_legacy(m))
                 } else {
-                    ctx.text(
-                        SimpleMessage {
-                            message: "!!! message must starts with /".to_string(),
-                        }
-                        .get_json_data(Status::Error, Event::Unknown),
-                    );
+                    protocol::parse_request(m)
+                };
+                match request {
+                    Ok(request) => self.handle_request(request_id, request, ctx),
+                    Err((event, error)) => reply(ctx, &request_id, WsResponse::error(event, error)),
                 }
             }
             ws::Message::Binary(_) => println!("Unexpected binary"),
@@ -265,6 +149,618 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
 }
 
 impl WsChatSession {
+    pub fn new(
+        addr: Addr<room_manager::ChatServer>,
+        version: u32,
+        announce_version: bool,
+    ) -> WsChatSession {
+        WsChatSession {
+            id: Uuid::new_v4(),
+            resume_token: Uuid::nil(),
+            hb: Instant::now(),
+            // defaultルームへの割り当てなし
+            room: None,
+            room_id: None,
+            version,
+            announce_version,
+            addr,
+        }
+    }
+
+    /// Dispatch request from client
+    fn handle_request(
+        &mut self,
+        request_id: Option<RequestId>,
+        request: WsRequest,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match request {
+            WsRequest::Hello { version, client_id } => match protocol::negotiate_version(version) {
+                Some(version) => {
+                    if let Some(client_id) = client_id {
+                        if let Err(error) = room::validate_client_id(&client_id) {
+                            reply(ctx, &request_id, WsResponse::error(Event::Hello, error));
+                            return;
+                        }
+                        self.addr.do_send(SetClientId {
+                            session_id: self.id,
+                            client_id,
+                        });
+                    }
+                    self.version = version;
+                    self.send_hello(&request_id, ctx);
+                }
+                None => {
+                    // client is too old to talk with
+                    reply(ctx, &request_id, protocol::unsupported_version(version));
+                    ctx.close(Some(ws::CloseCode::Policy.into()));
+                    ctx.stop();
+                }
+            },
+            WsRequest::Resume { resume_token } => self
+                .addr
+                .send(Resume {
+                    session_id: self.id,
+                    resume_token,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    match res {
+                        Ok(Some(resumed)) => {
+                            act.id = resumed.info.session_id;
+                            act.resume_token = resumed.info.resume_token;
+                            act.room_id = resumed.info.room.as_ref().map(|room| room.id);
+                            act.room = resumed.room;
+                            reply(ctx, &request_id, WsResponse::Resumed(resumed.info));
+                        }
+                        Ok(None) => reply(
+                            ctx,
+                            &request_id,
+                            WsResponse::error(
+                                Event::Resume,
+                                ServerError::new(
+                                    ErrorCode::SessionExpired,
+                                    "session is expired or resume token is invalid",
+                                ),
+                            ),
+                        ),
+                        _ => reply(
+                            ctx,
+                            &request_id,
+                            WsResponse::error(Event::Resume, ServerError::internal()),
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::List => {
+                // Send ListRooms message to chat server and wait for
+                // response
+                println!("List rooms");
+                self.addr
+                    .send(ListRooms)
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        match res {
+                            Ok(rooms) => reply(ctx, &request_id, WsResponse::GetRoomList(rooms)),
+                            _ => reply(
+                                ctx,
+                                &request_id,
+                                WsResponse::error(Event::GetRoomList, ServerError::internal()),
+                            ),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+                // .wait(ctx) pauses all events in context,
+                // so actor wont receive any new messages until it get list
+                // of rooms back
+            }
+            WsRequest::Subscribe { query } => self
+                .addr
+                .send(Subscribe {
+                    session_id: self.id,
+                    query,
+                })
+                .into_actor(self)
+                .then(move |res, _, ctx| {
+                    let response = match res {
+                        Ok(Ok(rooms)) => WsResponse::Subscribed(rooms),
+                        Ok(Err(error)) => WsResponse::error(Event::Subscribe, error),
+                        _ => WsResponse::error(Event::Subscribe, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::Unsubscribe => self
+                .addr
+                .send(Unsubscribe {
+                    session_id: self.id,
+                })
+                .into_actor(self)
+                .then(move |res, _, ctx| {
+                    let response = match res {
+                        Ok(Ok(())) => WsResponse::Unsubscribed,
+                        Ok(Err(error)) => WsResponse::error(Event::Unsubscribe, error),
+                        _ => WsResponse::error(Event::Unsubscribe, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::Join {
+                room_id,
+                password,
+                spectate,
+            } => self
+                .addr
+                .send(Join {
+                    session_id: self.id,
+                    room_id,
+                    password,
+                    spectate,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    let response = match res {
+                        Ok(Ok(joined)) => {
+                            act.enter(joined.info.id, joined.addr);
+                            WsResponse::EnterRoom(joined.info)
+                        }
+                        Ok(Err(error)) => WsResponse::error(Event::EnterRoom, error),
+                        _ => WsResponse::error(Event::EnterRoom, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::JoinByCode {
+                invite_code,
+                password,
+                spectate,
+            } => self
+                .addr
+                .send(JoinByCode {
+                    session_id: self.id,
+                    invite_code,
+                    password,
+                    spectate,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    let response = match res {
+                        Ok(Ok(joined)) => {
+                            act.enter(joined.info.id, joined.addr);
+                            WsResponse::EnterRoom(joined.info)
+                        }
+                        Ok(Err(error)) => WsResponse::error(Event::EnterRoom, error),
+                        _ => WsResponse::error(Event::EnterRoom, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::Matchmake { tag } => self
+                .addr
+                .send(Matchmake {
+                    session_id: self.id,
+                    tag,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    match res {
+                        Ok(Ok(ticket)) => {
+                            reply(ctx, &request_id, WsResponse::Queued(ticket.info));
+                            act.wait_for_match(ticket.matched, ctx);
+                        }
+                        Ok(Err(error)) => {
+                            reply(ctx, &request_id, WsResponse::error(Event::Matchmake, error))
+                        }
+                        _ => reply(
+                            ctx,
+                            &request_id,
+                            WsResponse::error(Event::Matchmake, ServerError::internal()),
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::CancelMatchmaking => self
+                .addr
+                .send(CancelMatchmaking {
+                    session_id: self.id,
+                })
+                .into_actor(self)
+                .then(move |res, _, ctx| {
+                    let response = match res {
+                        Ok(Ok(info)) => WsResponse::MatchmakingCancelled(info),
+                        Ok(Err(error)) => WsResponse::error(Event::CancelMatchmaking, error),
+                        _ => WsResponse::error(Event::CancelMatchmaking, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::Leave => self
+                .addr
+                .send(Leave {
+                    session_id: self.id,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    let response = match res {
+                        Ok(Ok(room_info)) => {
+                            act.room = None;
+                            act.room_id = None;
+                            WsResponse::LeaveRoom(room_info)
+                        }
+                        Ok(Err(error)) => WsResponse::error(Event::LeaveRoom, error),
+                        _ => WsResponse::error(Event::LeaveRoom, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::SetName { name } => self
+                .addr
+                .send(SetName {
+                    session_id: self.id,
+                    name,
+                })
+                .into_actor(self)
+                .then(move |res, _, ctx| {
+                    let response = match res {
+                        Ok(Ok(name)) => WsResponse::SetName(name),
+                        Ok(Err(error)) => WsResponse::error(Event::SetName, error),
+                        _ => WsResponse::error(Event::SetName, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::Chat { text } => {
+                if let Some(room) = self.joined_room(&request_id, Event::Chat, ctx) {
+                    room.send(SendChat {
+                        session_id: self.id,
+                        text,
+                    })
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        let response = match res {
+                            Ok(Ok(entry)) => WsResponse::Chat(entry),
+                            Ok(Err(error)) => WsResponse::error(Event::Chat, error),
+                            _ => WsResponse::error(Event::Chat, ServerError::internal()),
+                        };
+                        reply(ctx, &request_id, response);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+                }
+            }
+            WsRequest::Create {
+                name,
+                settings,
+                password,
+            } => self
+                .addr
+                .send(Create {
+                    session_id: self.id,
+                    room_name: name,
+                    settings,
+                    password,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    let response = match res {
+                        Ok(Ok(joined)) => {
+                            // creator enters the room
+                            act.enter(joined.info.id, joined.addr);
+                            WsResponse::CreateRoom(joined.info)
+                        }
+                        Ok(Err(error)) => WsResponse::error(Event::CreateRoom, error),
+                        _ => WsResponse::error(Event::CreateRoom, ServerError::internal()),
+                    };
+                    reply(ctx, &request_id, response);
+                    fut::ready(())
+                })
+                .wait(ctx),
+            WsRequest::Kick { session_id } => self.kick(request_id, session_id, false, ctx),
+            WsRequest::Ban { session_id } => self.kick(request_id, session_id, true, ctx),
+            WsRequest::Lock { locked } => {
+                if let Some(room) = self.joined_room(&request_id, Event::LockRoom, ctx) {
+                    room.send(Lock {
+                        session_id: self.id,
+                        locked,
+                    })
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        let response = match res {
+                            Ok(Ok(room_info)) => WsResponse::LockRoom(room_info),
+                            Ok(Err(error)) => WsResponse::error(Event::LockRoom, error),
+                            _ => WsResponse::error(Event::LockRoom, ServerError::internal()),
+                        };
+                        reply(ctx, &request_id, response);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+                }
+            }
+            WsRequest::Ready { ready } => {
+                if let Some(room) = self.joined_room(&request_id, Event::Ready, ctx) {
+                    room.send(SetReady {
+                        session_id: self.id,
+                        ready,
+                    })
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        let response = match res {
+                            Ok(Ok(entry)) => WsResponse::Ready(entry),
+                            Ok(Err(error)) => WsResponse::error(Event::Ready, error),
+                            _ => WsResponse::error(Event::Ready, ServerError::internal()),
+                        };
+                        reply(ctx, &request_id, response);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+                }
+            }
+            WsRequest::Finish => {
+                if let Some(room) = self.joined_room(&request_id, Event::FinishGame, ctx) {
+                    room.send(FinishGame {
+                        session_id: self.id,
+                    })
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        let response = match res {
+                            Ok(Ok(room_info)) => WsResponse::FinishGame(room_info),
+                            Ok(Err(error)) => WsResponse::error(Event::FinishGame, error),
+                            _ => WsResponse::error(Event::FinishGame, ServerError::internal()),
+                        };
+                        reply(ctx, &request_id, response);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+                }
+            }
+            WsRequest::FirstCards { cards } => self.send_to_room(&request_id, true, cards, ctx),
+            WsRequest::Cards { cards } => self.send_to_room(&request_id, false, cards, ctx),
+            WsRequest::MoveCards { moves } => {
+                if let Some(room) = self.joined_room(&request_id, Event::MoveCards, ctx) {
+                    room.send(MoveCards {
+                        session_id: self.id,
+                        moves,
+                    })
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        let response = match res {
+                            Ok(Ok(state)) => WsResponse::CardsMoved(state),
+                            Ok(Err(error)) => WsResponse::error(Event::MoveCards, error),
+                            _ => WsResponse::error(Event::MoveCards, ServerError::internal()),
+                        };
+                        reply(ctx, &request_id, response);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+                }
+            }
+            WsRequest::PlayDeck { deck_id } => {
+                if let Some(room) = self.joined_room(&request_id, Event::PlayDeck, ctx) {
+                    room.send(PlayDeck {
+                        session_id: self.id,
+                        deck_id,
+                    })
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        let response = match res {
+                            Ok(Ok(state)) => WsResponse::DeckDealt(state),
+                            Ok(Err(error)) => WsResponse::error(Event::PlayDeck, error),
+                            _ => WsResponse::error(Event::PlayDeck, ServerError::internal()),
+                        };
+                        reply(ctx, &request_id, response);
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+                }
+            }
+            WsRequest::Replay { after } => match &self.room {
+                Some(room) => room
+                    .send(Replay {
+                        session_id: self.id,
+                        after,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Some(replayed)) => {
+                                for message in replayed.messages {
+                                    if let Some(message) =
+                                        protocol::for_version(act.version, message)
+                                    {
+                                        ctx.text(message);
+                                    }
+                                }
+                                reply(
+                                    ctx,
+                                    &request_id,
+                                    WsResponse::Replayed(ReplayInfo {
+                                        last_seq: replayed.last_seq,
+                                    }),
+                                );
+                            }
+                            Ok(None) => reply(
+                                ctx,
+                                &request_id,
+                                WsResponse::error(
+                                    Event::Replay,
+                                    ServerError::new(
+                                        ErrorCode::ReplayUnavailable,
+                                        "messages are no longer available, reload room",
+                                    ),
+                                ),
+                            ),
+                            _ => reply(
+                                ctx,
+                                &request_id,
+                                WsResponse::error(Event::Replay, ServerError::internal()),
+                            ),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx),
+                None => reply(
+                    ctx,
+                    &request_id,
+                    WsResponse::error(Event::Replay, ServerError::not_in_room()),
+                ),
+            },
+        }
+    }
+
+    /// Tell negotiated version, and session info if client can resume
+    fn send_hello(&self, request_id: &Option<RequestId>, ctx: &mut ws::WebsocketContext<Self>) {
+        reply(
+            ctx,
+            request_id,
+            WsResponse::Hello(protocol::protocol_info(self.version)),
+        );
+        if protocol::supports(self.version, "resume") {
+            ctx.text(
+                WsResponse::Session(SessionInfo {
+                    session_id: self.id,
+                    resume_token: self.resume_token,
+                    room: None,
+                })
+                .to_json(),
+            );
+        }
+    }
+
+    /// Keep link to joined room
+    fn enter(&mut self, room_id: Uuid, room: RoomLink) {
+        self.room_id = Some(room_id);
+        self.room = Some(room);
+    }
+
+    /// Joined room, answers `NotInRoom` for `event` if not joined
+    fn joined_room(
+        &self,
+        request_id: &Option<RequestId>,
+        event: Event,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Option<RoomLink> {
+        if self.room.is_none() {
+            reply(
+                ctx,
+                request_id,
+                WsResponse::error(event, ServerError::not_in_room()),
+            );
+        }
+        self.room.clone()
+    }
+
+    /// Enter room once match is found, without pausing other requests
+    /// so that client can cancel meanwhile
+    fn wait_for_match(
+        &self,
+        matched: oneshot::Receiver<Result<JoinedRoom, ServerError>>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        matched
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(joined)) => {
+                        act.enter(joined.info.id, joined.addr);
+                        ctx.text(WsResponse::MatchFound(joined.info).to_json());
+                    }
+                    Ok(Err(error)) => {
+                        ctx.text(WsResponse::error(Event::MatchFound, error).to_json())
+                    }
+                    // left queue
+                    Err(_) => (),
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Ask room to remove member, only its host can
+    fn kick(
+        &self,
+        request_id: Option<RequestId>,
+        target: Uuid,
+        ban: bool,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let room = match self.joined_room(&request_id, Event::Kicked, ctx) {
+            Some(room) => room,
+            None => return,
+        };
+        room.send(Kick {
+            session_id: self.id,
+            target,
+            ban,
+        })
+        .into_actor(self)
+        .then(move |res, _, ctx| {
+            let response = match res {
+                Ok(Ok(info)) => WsResponse::Kicked(info),
+                Ok(Err(error)) => WsResponse::error(Event::Kicked, error),
+                _ => WsResponse::error(Event::Kicked, ServerError::internal()),
+            };
+            reply(ctx, &request_id, response);
+            fut::ready(())
+        })
+        .wait(ctx)
+    }
+
+    /// Send cards to other members in joined room
+    fn send_to_room(
+        &self,
+        request_id: &Option<RequestId>,
+        first: bool,
+        cards: Vec<CardInfo>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let event = if first {
+            Event::FirstCardsInfo
+        } else {
+            Event::CardsInfo
+        };
+        match &self.room {
+            Some(room) => {
+                let request_id = request_id.clone();
+                room.send(Message {
+                    id: self.id,
+                    first,
+                    cards: CardInfoList { cards },
+                    legacy: !protocol::supports(self.version, "room-state"),
+                })
+                .into_actor(self)
+                .then(move |res, _, ctx| {
+                    // only failures are answered
+                    match res {
+                        Ok(Ok(())) => (),
+                        Ok(Err(error)) => reply(ctx, &request_id, WsResponse::error(event, error)),
+                        _ => reply(
+                            ctx,
+                            &request_id,
+                            WsResponse::error(event, ServerError::internal()),
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx)
+            }
+            None => reply(
+                ctx,
+                request_id,
+                WsResponse::error(event, ServerError::not_in_room()),
+            ),
+        }
+    }
+
     /// helper method that sends ping to client every second.
     ///
     /// also this method checks heartbeats from client
@@ -276,7 +772,10 @@ impl WsChatSession {
                 println!("Websocket Client heartbeat failed, disconnecting!");
 
                 // notify chat server
-                act.addr.do_send(Disconnect { id: act.id });
+                act.addr.do_send(Disconnect {
+                    id: act.id,
+                    resume_token: act.resume_token,
+                });
 
                 // stop actor
                 ctx.stop();
@@ -289,3 +788,12 @@ impl WsChatSession {
         });
     }
 }
+
+/// Send response to client, echoing id of the request it answers
+fn reply(
+    ctx: &mut ws::WebsocketContext<WsChatSession>,
+    request_id: &Option<RequestId>,
+    response: WsResponse,
+) {
+    ctx.text(response.to_json_for(request_id.as_ref()));
+}